@@ -0,0 +1,588 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::pin::Pin;
+use crate::io::{PAGE_SIZE, PageIdWidth, check_page_size, invalid_data, invalid_input, put_u16, put_u32};
+use crate::io::bitmap::BitmapPage;
+use crate::io::index::IndexPage;
+use crate::io::store::PageStore;
+
+const HEADER_PAGE_ID: usize = 0;
+const SEQUENCE_PAGE_ID: usize = 1;
+const FIRST_BITMAP_PAGE_ID: u64 = 2;
+
+const MAGIC: &[u8; 7] = b"EMBEDB\0";
+const FORMAT_VERSION: u16 = 1;
+const VERSION_OFFSET: usize = 8;
+const ROOT_INDEX_OFFSET: usize = 12;
+const PAGE_SIZE_OFFSET: usize = 16;
+const PAGE_COUNT_OFFSET: usize = 20;
+const PAGE_ID_WIDTH_OFFSET: usize = 24;
+// databases with u64 page ids keep the upper halves of the root index and the page count here, zero otherwise
+const ROOT_INDEX_HIGH_OFFSET: usize = 28;
+const PAGE_COUNT_HIGH_OFFSET: usize = 32;
+// zero for databases created before they had a sequence page, their page 1 belongs to the allocator
+const SEQUENCE_PAGE_OFFSET: usize = 36;
+const HEADER_SIZE: usize = 40;
+
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    version: u16,
+    page_size: u32,
+    page_id_width: PageIdWidth,
+    root_index_page_id: u64,
+    page_count: u64,
+    sequence_page_id: Option<usize>,
+}
+
+pub struct Database {
+    store: PageStore,
+    index: Pin<Box<IndexPage>>,
+    sequence_page_id: Option<usize>,
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(path: P, max_size: usize) -> Result<Database> {
+        let file = open_file(path)?;
+        let page_size = stored_layout(&file)?.map_or(PAGE_SIZE, |(page_size, _, _)| page_size);
+        Database::from_file(file, max_size, page_size, PageIdWidth::U32)
+    }
+
+    pub fn open_with_page_size<P: AsRef<Path>>(path: P, max_size: usize, page_size: usize) -> Result<Database> {
+        let file = open_file(path)?;
+        match stored_layout(&file)? {
+            Some((stored, _, _)) if stored != page_size => invalid_input(
+                format!("page size mismatch, database uses {} byte pages but {} were requested", stored, page_size)
+            ),
+            _ => Database::from_file(file, max_size, page_size, PageIdWidth::U32),
+        }
+    }
+
+    // the page id width is fixed when the database is created, u64 page ids address more than MAX_PAGE_ID pages
+    pub fn open_with_page_id_width<P: AsRef<Path>>(path: P, max_size: usize, page_size: usize, width: PageIdWidth) -> Result<Database> {
+        let file = open_file(path)?;
+        match stored_layout(&file)? {
+            Some((stored, _, _)) if stored != page_size => invalid_input(
+                format!("page size mismatch, database uses {} byte pages but {} were requested", stored, page_size)
+            ),
+            Some((_, _, stored)) if stored != width => invalid_input(
+                format!("page id width mismatch, database uses {} byte page ids but {} were requested", stored.bytes(), width.bytes())
+            ),
+            _ => Database::from_file(file, max_size, page_size, width),
+        }
+    }
+
+    // the page size is read from the header, so only an existing database can be opened read-only
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Database> {
+        let file = File::open(path)?;
+        let page_size = match stored_layout(&file)? {
+            Some((page_size, _, _)) => page_size,
+            None => return invalid_input("empty file, a database has to exist to be opened read-only"),
+        };
+        check_file_length(&file, page_size)?;
+        let mut store = PageStore::open_read_only(file, page_size)?;
+        store.set_reserved_pages(HEADER_PAGE_ID + 1);
+        Database::load(store)
+    }
+
+    fn from_file(file: File, max_size: usize, page_size: usize, width: PageIdWidth) -> Result<Database> {
+        check_page_size(page_size)?;
+        let is_new = file.metadata()?.len() == 0;
+        check_file_length(&file, page_size)?;
+
+        let mut store = PageStore::with_page_size(file, max_size, page_size)?;
+        store.set_reserved_pages(HEADER_PAGE_ID + 1);
+
+        if is_new {
+            let bitmap = BitmapPage::with_layout(FIRST_BITMAP_PAGE_ID, FIRST_BITMAP_PAGE_ID, page_size, width)?;
+            let index = IndexPage::grow(bitmap)?;
+            configure_sequence_page(&mut store, SEQUENCE_PAGE_ID)?;
+            let mut database = Database { store, index, sequence_page_id: Some(SEQUENCE_PAGE_ID) };
+            database.persist()?;
+            return Ok(database);
+        }
+        Database::load(store)
+    }
+
+    fn load(mut store: PageStore) -> Result<Database> {
+        let header = read_header(&store)?;
+        if let Some(id) = header.sequence_page_id {
+            configure_sequence_page(&mut store, id)?;
+        }
+        let memory = store.read_page(header.root_index_page_id as usize)?;
+        let index = IndexPage::load(&memory, &store, |_| true)?;
+        drop(memory);
+        match index {
+            Some(index) => Ok(Database { store, index, sequence_page_id: header.sequence_page_id }),
+            None => invalid_data(
+                format!("invalid database, root index page {} could not be loaded", header.root_index_page_id)
+            ),
+        }
+    }
+
+    pub fn persist(&mut self) -> Result<()> {
+        self.index.persist(&mut self.store)?;
+        self.write_header()?;
+        self.store.flush()
+    }
+
+    pub fn allocate(&mut self) -> Result<Option<u64>> {
+        self.index.allocate(&self.store, &mut |_| true)
+    }
+
+    pub fn free(&mut self, page_id: u64) -> Result<Option<bool>> {
+        self.index.free(page_id, &self.store, &mut |_| true)
+    }
+
+    // the reservation lasts until the database is closed, the reserved pages stay allocated on disk
+    pub fn reserve(&mut self, pages: Range<u64>) -> Result<bool> {
+        self.index.reserve(pages, &self.store)
+    }
+
+    pub fn is_allocated(&self, page_id: u64) -> Result<Option<bool>> {
+        self.index.is_allocated(page_id, &self.store)
+    }
+
+    pub fn next_sequence(&mut self) -> Result<u64> {
+        self.next_sequence_block(1)
+    }
+
+    // the counter lives on its own reserved page, so persisting the header never resets it
+    pub fn next_sequence_block(&mut self, count: u64) -> Result<u64> {
+        match self.sequence_page_id {
+            Some(_) => self.store.next_sequence_block(count),
+            None => invalid_input("no sequence page, the database was created before sequences were supported"),
+        }
+    }
+
+    // the header switches to the new allocator only once it is durable, so a crash leaves either the old or
+    // the new allocator live. the old allocator's pages are freed in the new one once the switch is durable
+    pub fn install_allocator(&mut self, mut index: Pin<Box<IndexPage>>) -> Result<()> {
+        let root_index_page_id = read_header(&self.store)?.root_index_page_id;
+        let memory = self.store.read_page(root_index_page_id as usize)?;
+        let live_pages = IndexPage::view(&memory)?.metadata_pages(&self.store)?;
+        drop(memory);
+        if let Some(page_id) = index.pending_writes().into_iter().find(|page_id| live_pages.iter().any(|(live, _, _)| live == page_id)) {
+            return invalid_input(
+                format!("invalid allocator, persisting it would overwrite page {} of the live allocator", page_id)
+            );
+        }
+
+        let mut old_pages = self.index.metadata_pages(&self.store)?;
+        old_pages.extend(live_pages);
+
+        index.persist(&mut self.store)?;
+        self.store.flush()?;
+        self.index = index;
+        self.write_header()?;
+        self.store.flush()?;
+
+        let new_pages = self.index.metadata_pages(&self.store)?;
+        for (page_id, _, _) in old_pages {
+            if !new_pages.iter().any(|(new, _, _)| *new == page_id) {
+                self.index.free(page_id, &self.store, &mut |_| true)?;
+            }
+        }
+        self.persist()
+    }
+
+    pub fn store(&self) -> &PageStore {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut PageStore {
+        &mut self.store
+    }
+
+    pub fn index(&self) -> &IndexPage {
+        &self.index
+    }
+
+    pub fn page_id_width(&self) -> PageIdWidth {
+        self.index.id_width()
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let root_index_page_id = self.index.page_id();
+        let page_count = self.store.stats().page_count as u64;
+        let mut buffer = vec![0u8; self.store.page_size()];
+        buffer[..MAGIC.len()].copy_from_slice(MAGIC);
+        put_u16(&mut buffer, VERSION_OFFSET, FORMAT_VERSION);
+        put_u32(&mut buffer, ROOT_INDEX_OFFSET, root_index_page_id as u32);
+        put_u32(&mut buffer, PAGE_SIZE_OFFSET, self.store.page_size() as u32);
+        put_u32(&mut buffer, PAGE_COUNT_OFFSET, page_count as u32);
+        put_u16(&mut buffer, PAGE_ID_WIDTH_OFFSET, self.page_id_width().bytes() as u16);
+        put_u32(&mut buffer, ROOT_INDEX_HIGH_OFFSET, (root_index_page_id >> 32) as u32);
+        put_u32(&mut buffer, PAGE_COUNT_HIGH_OFFSET, (page_count >> 32) as u32);
+        put_u32(&mut buffer, SEQUENCE_PAGE_OFFSET, self.sequence_page_id.map_or(0, |id| id as u32));
+        Ok(self.store.write_metadata_page(HEADER_PAGE_ID, &buffer)?)
+    }
+}
+
+fn open_file<P: AsRef<Path>>(path: P) -> Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+// the page size has to be known before the store can compute any page offsets, and the page count before
+// it maps a file that was cut short
+fn stored_layout(mut file: &File) -> Result<Option<(usize, u64, PageIdWidth)>> {
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    let mut bytes = [0u8; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut bytes)?;
+    if &bytes[..MAGIC.len()] != MAGIC {
+        return invalid_data("not an embedb database, magic number mismatch");
+    }
+    let page_size = header_u32(&bytes, PAGE_SIZE_OFFSET);
+    let page_count = header_u64(&bytes, PAGE_COUNT_OFFSET, PAGE_COUNT_HIGH_OFFSET);
+    let width = page_id_width(u16::from_le_bytes([bytes[PAGE_ID_WIDTH_OFFSET], bytes[PAGE_ID_WIDTH_OFFSET + 1]]))?;
+    Ok(Some((page_size as usize, page_count, width)))
+}
+
+fn configure_sequence_page(store: &mut PageStore, id: usize) -> Result<()> {
+    store.set_reserved_pages(id + 1);
+    store.set_sequence_page(id)
+}
+
+fn page_id_width(bytes: u16) -> Result<PageIdWidth> {
+    match bytes {
+        4 => Ok(PageIdWidth::U32),
+        8 => Ok(PageIdWidth::U64),
+        _ => invalid_data(format!("unsupported page id width of {} bytes", bytes)),
+    }
+}
+
+fn header_u32(header: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes([header[idx], header[idx + 1], header[idx + 2], header[idx + 3]])
+}
+
+fn header_u64(header: &[u8], low: usize, high: usize) -> u64 {
+    (header_u32(header, high) as u64) << 32 | header_u32(header, low) as u64
+}
+
+// a file cut short of the pages its header records would fault on first access through the mapping
+fn check_file_length(file: &File, page_size: usize) -> Result<()> {
+    if let Some((_, page_count, _)) = stored_layout(file)? {
+        let file_len = file.metadata()?.len();
+        let expected_len = page_count.saturating_mul(page_size as u64);
+        if file_len < expected_len {
+            return invalid_data(
+                format!("file truncated, database expects {} bytes ({} pages) but the file has {}", expected_len, page_count, file_len)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn read_header(store: &PageStore) -> Result<Header> {
+    let header = store.read_page(HEADER_PAGE_ID)?;
+    if &header.content()[..MAGIC.len()] != MAGIC {
+        return invalid_data("not an embedb database, magic number mismatch");
+    }
+    let version = header.get_u16(VERSION_OFFSET);
+    if version != FORMAT_VERSION {
+        return invalid_data(
+            format!("unsupported database format version {} (expected {})", version, FORMAT_VERSION)
+        );
+    }
+    let page_size = header.get_u32(PAGE_SIZE_OFFSET);
+    if page_size as usize != store.page_size() {
+        return invalid_data(
+            format!("page size mismatch, database uses {} byte pages but the store is configured for {}", page_size, store.page_size())
+        );
+    }
+    let content = header.content();
+    Ok(Header {
+        version,
+        page_size,
+        page_id_width: page_id_width(header.get_u16(PAGE_ID_WIDTH_OFFSET))?,
+        root_index_page_id: header_u64(content, ROOT_INDEX_OFFSET, ROOT_INDEX_HIGH_OFFSET),
+        page_count: header_u64(content, PAGE_COUNT_OFFSET, PAGE_COUNT_HIGH_OFFSET),
+        sequence_page_id: match header.get_u32(SEQUENCE_PAGE_OFFSET) {
+            0 => None,
+            id => Some(id as usize),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{read_header, Database, Header, FIRST_BITMAP_PAGE_ID, FORMAT_VERSION, HEADER_PAGE_ID, SEQUENCE_PAGE_ID, VERSION_OFFSET};
+    use crate::io::bitmap::{bitmap_page_count, BitmapPage, BITMAP_PAGE_COUNT};
+    use crate::io::index::IndexPage;
+    use crate::io::{PageIdWidth, PageType};
+    use std::fs::{self, OpenOptions};
+    use std::io::{ErrorKind, Seek, SeekFrom, Write};
+    use tempfile::tempdir;
+
+    const TESTDB_MAX_SIZE: usize = 3 * BITMAP_PAGE_COUNT as usize * 4096;
+
+    #[test]
+    fn reopens_database_with_allocation_state() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let first = database.allocate().unwrap().unwrap();
+        database.persist().unwrap();
+        drop(database);
+
+        let mut reopened = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let second = reopened.allocate().unwrap().unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(Some(true), reopened.free(first).unwrap());
+    }
+
+    #[test]
+    fn cannot_free_unmanaged_pages() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+
+        assert_eq!(None, database.free(HEADER_PAGE_ID as u64).unwrap());
+        assert_eq!(None, database.free(SEQUENCE_PAGE_ID as u64).unwrap());
+        database.persist().unwrap();
+        drop(database);
+
+        assert!(Database::open(&path, TESTDB_MAX_SIZE).is_ok());
+    }
+
+    #[test]
+    fn reserved_pages_stay_allocated() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+
+        assert!(database.reserve(10..50).unwrap());
+        for _ in 0..100 {
+            let page = database.allocate().unwrap().unwrap();
+            assert!(!(10..50).contains(&page));
+        }
+        assert!((10..50).all(|page| database.is_allocated(page).unwrap() == Some(true)));
+    }
+
+    #[test]
+    fn installs_rebuilt_allocator() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let data = database.allocate().unwrap().unwrap();
+        database.persist().unwrap();
+
+        let root_index_page_id = read_header(database.store()).unwrap().root_index_page_id;
+        let memory = database.store().read_page(root_index_page_id as usize).unwrap();
+        let old_pages = IndexPage::view(&memory).unwrap().metadata_pages(database.store()).unwrap();
+        let rebuilt = IndexPage::load(&memory, database.store(), |_| true).unwrap().unwrap();
+        drop(memory);
+        let rebuilt_pages = rebuilt.metadata_pages(database.store()).unwrap();
+        let rebuilt_page_id = rebuilt.page_id();
+
+        database.install_allocator(rebuilt).unwrap();
+        for (page_id, _, _) in old_pages {
+            let shared = rebuilt_pages.iter().any(|(rebuilt, _, _)| *rebuilt == page_id);
+            assert_eq!(Some(shared), database.is_allocated(page_id).unwrap(), "page {}", page_id);
+        }
+        drop(database);
+
+        let reopened = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(rebuilt_page_id, read_header(reopened.store()).unwrap().root_index_page_id);
+        assert_eq!(Some(true), reopened.is_allocated(data).unwrap());
+    }
+
+    #[test]
+    fn cannot_install_allocator_over_live_pages() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let root_index_page_id = database.index().page_id();
+
+        let index = IndexPage::grow(BitmapPage::new(FIRST_BITMAP_PAGE_ID).unwrap()).unwrap();
+        let error = database.install_allocator(index).err().unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!(root_index_page_id, read_header(database.store()).unwrap().root_index_page_id);
+    }
+
+    #[test]
+    fn sequence_continues_after_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        let mut database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(0, database.next_sequence().unwrap());
+        assert_eq!(1, database.next_sequence_block(10).unwrap());
+        database.persist().unwrap();
+        assert_eq!(11, database.next_sequence().unwrap());
+        drop(database);
+
+        let mut reopened = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(12, reopened.next_sequence().unwrap());
+        reopened.persist().unwrap();
+        drop(reopened);
+
+        let mut reopened = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(13, reopened.next_sequence().unwrap());
+        assert_eq!(None, reopened.is_allocated(SEQUENCE_PAGE_ID as u64).unwrap());
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        let database = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let header = read_header(database.store()).unwrap();
+
+        let page_count = fs::metadata(&path).unwrap().len() / 4096;
+        let expected = Header { version: FORMAT_VERSION, page_size: 4096, page_id_width: PageIdWidth::U32, root_index_page_id: database.index().page_id(), page_count, sequence_page_id: Some(SEQUENCE_PAGE_ID) };
+        assert_eq!(expected, header);
+    }
+
+    #[test]
+    fn cannot_open_foreign_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("foreign.db");
+        fs::write(&path, vec![0xAB; 4096]).unwrap();
+
+        let result = Database::open(&path, TESTDB_MAX_SIZE);
+
+        let error = result.err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("magic number"));
+    }
+
+    #[test]
+    fn reopens_with_stored_page_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+
+        let database = Database::open_with_page_size(&path, TESTDB_MAX_SIZE, 4096).unwrap();
+        assert_eq!(4096, database.store().page_size());
+
+        let error = Database::open_with_page_size(&path, TESTDB_MAX_SIZE, 8192).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("database uses 4096 byte pages but 8192 were requested"));
+    }
+
+    #[test]
+    fn cannot_create_database_with_unsupported_page_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        let error = Database::open_with_page_size(&path, TESTDB_MAX_SIZE, 256).err().unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!(0, fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn creates_and_reopens_database_with_larger_pages() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let page_size = 8192;
+        let max_size = 3 * bitmap_page_count(page_size, PageIdWidth::U32) as usize * page_size;
+
+        let mut database = Database::open_with_page_size(&path, max_size, page_size).unwrap();
+        let first = database.allocate().unwrap().unwrap();
+        database.persist().unwrap();
+        drop(database);
+
+        let mut reopened = Database::open(&path, max_size).unwrap();
+        assert_eq!(page_size, reopened.store().page_size());
+        assert_eq!(page_size as u32, read_header(reopened.store()).unwrap().page_size);
+        assert_eq!(Some(true), reopened.is_allocated(first).unwrap());
+        assert_ne!(first, reopened.allocate().unwrap().unwrap());
+        drop(reopened);
+
+        let read_only = Database::open_read_only(&path).unwrap();
+        assert!(read_only.store().is_read_only());
+        assert_eq!(page_size, read_only.store().page_size());
+        assert_eq!(Some(true), read_only.is_allocated(first).unwrap());
+    }
+
+    #[test]
+    fn creates_and_reopens_database_with_u64_page_ids() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        let mut database = Database::open_with_page_id_width(&path, TESTDB_MAX_SIZE, 4096, PageIdWidth::U64).unwrap();
+        assert_eq!(PageIdWidth::U64, database.page_id_width());
+        let first = database.allocate().unwrap().unwrap();
+        database.persist().unwrap();
+        drop(database);
+
+        let mut reopened = Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(PageIdWidth::U64, reopened.page_id_width());
+        assert_eq!(PageIdWidth::U64, read_header(reopened.store()).unwrap().page_id_width);
+        assert_eq!(Some(PageType::WideBitmap), reopened.store().read_page(FIRST_BITMAP_PAGE_ID as usize).unwrap().typed());
+        assert_eq!(Some(true), reopened.is_allocated(first).unwrap());
+        assert_ne!(first, reopened.allocate().unwrap().unwrap());
+        reopened.persist().unwrap();
+        assert!(reopened.store().verify_streaming(reopened.index(), false).unwrap().is_clean());
+        drop(reopened);
+
+        let error = Database::open_with_page_id_width(&path, TESTDB_MAX_SIZE, 4096, PageIdWidth::U32).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("database uses 8 byte page ids but 4 were requested"));
+    }
+
+    #[test]
+    fn cannot_open_missing_database_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        fs::write(&path, []).unwrap();
+
+        let error = Database::open_read_only(&path).err().unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+    }
+
+    #[test]
+    fn cannot_open_truncated_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len / 2).unwrap();
+
+        let error = Database::open(&path, TESTDB_MAX_SIZE).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains(&format!("file truncated, database expects {} bytes", len)), "{}", error);
+    }
+
+    #[test]
+    fn cannot_reopen_with_smaller_max_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+        let len = fs::metadata(&path).unwrap().len() as usize;
+
+        let error = Database::open(&path, len - 4096).err().unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(Database::open(&path, len).is_ok());
+    }
+
+    #[test]
+    fn cannot_open_unknown_format_version() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        Database::open(&path, TESTDB_MAX_SIZE).unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(VERSION_OFFSET as u64)).unwrap();
+        file.write_all(&(FORMAT_VERSION + 1).to_le_bytes()).unwrap();
+
+        let result = Database::open(&path, TESTDB_MAX_SIZE);
+
+        let error = result.err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+        assert!(error.to_string().contains("format version"));
+    }
+}
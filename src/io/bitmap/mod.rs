@@ -1,114 +1,350 @@
 use std::io::Result;
-use crate::io::{PAGE_SIZE, PageType};
-use crate::io::store::{MemoryPage, PageStore};
+use std::ops::Range;
+use crate::io::{MIN_PAGE_SIZE, PAGE_SIZE, PAGE_TRAILER_SIZE, PageIdWidth, check_page_size, expect_sized_page, header_first_managed_page_id, header_page_id, header_prefix_size, invalid_data, invalid_input, put_header_prefix, put_sentinel, check_sentinel, put_u16};
+use crate::io::store::{MemoryPage, PageHeaderSnapshot, PageStore};
 use std::pin::Pin;
 
-const BITMAP_HEADER_SIZE: usize = 16;
-pub(crate) const BITMAP_PAGE_COUNT: u16 = ((PAGE_SIZE - BITMAP_HEADER_SIZE) * 8) as u16;
+// bit indices are u16 with 0xFFFF marking no free page, so pages beyond 8K leave the rest of the page unused
+const MAX_BITMAP_PAGE_COUNT: usize = 0xFFF8;
+// pages managed by a bitmap on a default sized page
+pub const BITMAP_PAGE_COUNT: u16 = bitmap_page_count(PAGE_SIZE, PageIdWidth::U32);
+
+// page id 0..4, type 4..8, first managed page 8..12, free count 12..14, first free index 14..16, generation 16..20.
+// with u64 page ids the header prefix is 8 bytes longer and moves the rest along, see header_prefix_size
+const fn bitmap_header_size(width: PageIdWidth) -> usize {
+    header_prefix_size(width) + 8
+}
+
+const fn bitmap_generation_offset(width: PageIdWidth) -> usize {
+    header_prefix_size(width) + 4
+}
+
+const _: () = assert!(bitmap_generation_offset(PageIdWidth::U64) + 4 <= bitmap_header_size(PageIdWidth::U64));
+const _: () = assert!(bitmap_header_size(PageIdWidth::U64) + PAGE_TRAILER_SIZE < MIN_PAGE_SIZE);
+
+pub const fn bitmap_page_count(page_size: usize, width: PageIdWidth) -> u16 {
+    let bits = page_size.saturating_sub(bitmap_header_size(width) + PAGE_TRAILER_SIZE) * 8;
+    if bits > MAX_BITMAP_PAGE_COUNT {
+        MAX_BITMAP_PAGE_COUNT as u16
+    } else {
+        bits as u16
+    }
+}
+
+pub(crate) fn max_first_managed_page_id(page_size: usize, width: PageIdWidth) -> u64 {
+    width.max_page_id() - (bitmap_page_count(page_size, width) as u64 - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocOutcome {
+    Allocated(u64),
+    Full,
+    BudgetExhausted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    Full,
+    AllFiltered { scanned: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub start_page_id: u64,
+    pub length: u32,
+    pub allocated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeHintStatus {
+    Ok,
+    PointsAtUsed,
+    OutOfRange,
+}
 
 pub struct BitmapPage {
-    pub(crate) page_id: u32,
-    pub(crate) first_managed_page_id: u32,
-    last_managed_page_id: u32,
+    pub(crate) page_id: u64,
+    pub(crate) first_managed_page_id: u64,
+    last_managed_page_id: u64,
+    width: PageIdWidth,
     current_first_free_page_idx: u16,
     first_free_page_idx: u16,
     pub(crate) free_page_count: u16,
-    buffer: [u8; PAGE_SIZE],
+    generation: u32,
+    // changed since the last persist, either its bits or the page it lives on
+    dirty: bool,
+    buffer: Vec<u8>,
 }
 
 impl<'a> BitmapPage {
-    pub fn new(first_managed_page_id: u32) -> Pin<Box<BitmapPage>> {
-        let last_managed_page_id = first_managed_page_id + (BITMAP_PAGE_COUNT as u32) - 1;
+    pub fn new(first_managed_page_id: u64) -> Result<Pin<Box<BitmapPage>>> {
+        BitmapPage::new_at(first_managed_page_id, first_managed_page_id)
+    }
+
+    pub fn new_at(first_managed_page_id: u64, backing_page_id: u64) -> Result<Pin<Box<BitmapPage>>> {
+        BitmapPage::with_page_size(first_managed_page_id, backing_page_id, PAGE_SIZE)
+    }
 
-        let mut page = Box::pin(BitmapPage {
-            page_id: first_managed_page_id,
+    // larger pages manage more pages per bitmap, see bitmap_page_count
+    pub fn with_page_size(first_managed_page_id: u64, backing_page_id: u64, page_size: usize) -> Result<Pin<Box<BitmapPage>>> {
+        BitmapPage::with_layout(first_managed_page_id, backing_page_id, page_size, PageIdWidth::U32)
+    }
+
+    // u64 page ids manage pages beyond MAX_PAGE_ID, at the cost of a longer header
+    pub fn with_layout(first_managed_page_id: u64, backing_page_id: u64, page_size: usize, width: PageIdWidth) -> Result<Pin<Box<BitmapPage>>> {
+        let mut page = BitmapPage::sized(backing_page_id, first_managed_page_id, page_size, width)?;
+        if page.contains(backing_page_id) {
+            page.mark_used(backing_page_id, |_| true);
+        }
+        Ok(page)
+    }
+
+    pub fn new_external(page_id: u64, first_managed_page_id: u64) -> Result<Pin<Box<BitmapPage>>> {
+        BitmapPage::sized(page_id, first_managed_page_id, PAGE_SIZE, PageIdWidth::U32)
+    }
+
+    fn sized(page_id: u64, first_managed_page_id: u64, page_size: usize, width: PageIdWidth) -> Result<Pin<Box<BitmapPage>>> {
+        check_page_size(page_size)?;
+        let last_managed_page_id = last_managed_page_id(first_managed_page_id, page_size, width)?;
+
+        Ok(Box::pin(BitmapPage {
+            page_id,
             first_managed_page_id,
             last_managed_page_id,
+            width,
             current_first_free_page_idx: 0,
             first_free_page_idx: 0,
-            free_page_count: BITMAP_PAGE_COUNT,
-            buffer: [0; PAGE_SIZE],
-        });
-        page.mark_used(first_managed_page_id, |_| true);
-        page
+            free_page_count: bitmap_page_count(page_size, width),
+            generation: 0,
+            dirty: true,
+            buffer: vec![0; page_size],
+        }))
     }
 
-    pub fn load(page: &MemoryPage, mut f: impl FnMut(u32) -> bool) -> Option<Pin<Box<BitmapPage>>> {
-        let first_managed_page_id = page.get_u32(8);
-        let free_page_count = page.get_u16(12);
-        let first_free_page_idx = page.get_u16(14);
+    pub fn reset(&mut self, first_managed_page_id: u64) -> Result<()> {
+        self.last_managed_page_id = last_managed_page_id(first_managed_page_id, self.page_size(), self.width)?;
+        self.page_id = first_managed_page_id;
+        self.first_managed_page_id = first_managed_page_id;
+        self.current_first_free_page_idx = 0;
+        self.first_free_page_idx = 0;
+        self.free_page_count = self.managed_page_count();
+        self.dirty = true;
+        self.bitmap_mut().iter_mut().for_each(|byte| *byte = 0);
+        self.mark_used(first_managed_page_id, |_| true);
+        Ok(())
+    }
 
-        let bitmap = &page.content()[BITMAP_HEADER_SIZE..];
-        let mut filter = |x: u16| f(first_managed_page_id + x as u32);
+    pub(crate) fn from_bits(page_id: u64, first_managed_page_id: u64, page_size: usize, width: PageIdWidth, bits: &[u8]) -> Result<Pin<Box<BitmapPage>>> {
+        let mut page = BitmapPage::sized(page_id, first_managed_page_id, page_size, width)?;
+        page.bitmap_mut().copy_from_slice(bits);
 
-        let current_idx = bitmap.find_clear_filtered(first_free_page_idx, &mut filter)?;
-        let next_idx = bitmap.find_clear_filtered(current_idx + 1, &mut filter)?;
-        let page_id = first_managed_page_id + current_idx as u32;
+        let used_pages = bits.iter().map(|byte| byte.count_ones()).sum::<u32>();
+        let first_free_page_idx = page.bitmap().find_clear_filtered(0, |_| true).unwrap_or(0xFFFF);
+        page.free_page_count = page.managed_page_count() - used_pages as u16;
+        page.first_free_page_idx = first_free_page_idx;
+        page.current_first_free_page_idx = first_free_page_idx;
+        Ok(page)
+    }
+
+    pub(crate) fn reset_cursor(&mut self) {
+        self.current_first_free_page_idx = self.first_free_page_idx;
+    }
+
+    pub(crate) fn bits(&self) -> &[u8] {
+        self.bitmap()
+    }
 
-        let mut buffer = [0; PAGE_SIZE];
-        buffer.clone_from_slice(page.content());
+    pub fn page_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn managed_page_count(&self) -> u16 {
+        bitmap_page_count(self.buffer.len(), self.width)
+    }
+
+    pub fn id_width(&self) -> PageIdWidth {
+        self.width
+    }
+
+    pub fn load(page: &MemoryPage, mut f: impl FnMut(u64) -> bool) -> Result<Option<Pin<Box<BitmapPage>>>> {
+        let buffer = expect_sized_page(page.content())?.to_vec();
+        let width = PageIdWidth::of(&buffer);
+        let prefix = header_prefix_size(width);
+        let generation = check_sentinel(&buffer, bitmap_generation_offset(width))?;
+        let first_managed_page_id = checked_first_managed_page_id(&buffer)?;
+        let free_page_count = page.get_u16(prefix);
+        let first_free_page_idx = page.get_u16(prefix + 2);
+
+        let bitmap = &buffer[bitmap_header_size(width)..bitmap_end(buffer.len(), width)];
+        let mut filter = |x: u16| f(first_managed_page_id + x as u64);
+
+        let current_idx = match bitmap.find_clear_filtered(first_free_page_idx, &mut filter) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let next_idx = match bitmap.find_clear_filtered(current_idx + 1, &mut filter) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let page_id = first_managed_page_id + current_idx as u64;
+        let stored_page_id = header_page_id(&buffer);
 
         let mut index = Box::pin(BitmapPage {
             page_id,
             first_managed_page_id,
-            last_managed_page_id: first_managed_page_id + BITMAP_PAGE_COUNT as u32,
+            last_managed_page_id: last_managed_page_id(first_managed_page_id, buffer.len(), width)?,
+            width,
             current_first_free_page_idx: next_idx,
             first_free_page_idx,
             free_page_count,
+            generation,
+            dirty: true,
             buffer,
         });
         index.mark_used(page_id, filter);
-        index.free(page.page_id());
+        index.free(stored_page_id);
 
-        Some(index)
+        Ok(Some(index))
     }
 
-    pub fn load_into(page: &MemoryPage, page_id: u32) -> Pin<Box<BitmapPage>> {
-        let first_managed_page_id = page.get_u32(8);
-        let last_managed_page_id = first_managed_page_id + (BITMAP_PAGE_COUNT as u32) - 1;
-        let free_page_count = page.get_u16(12);
-        let first_free_page_idx = page.get_u16(14);
+    pub fn load_into(page: &MemoryPage, page_id: u64) -> Result<Pin<Box<BitmapPage>>> {
+        let buffer = expect_sized_page(page.content())?.to_vec();
+        let width = PageIdWidth::of(&buffer);
+        let prefix = header_prefix_size(width);
+        let generation = check_sentinel(&buffer, bitmap_generation_offset(width))?;
+        let first_managed_page_id = checked_first_managed_page_id(&buffer)?;
+        let last_managed_page_id = last_managed_page_id(first_managed_page_id, buffer.len(), width)?;
+        let free_page_count = page.get_u16(prefix);
+        let first_free_page_idx = page.get_u16(prefix + 2);
         let current_first_free_page_idx = first_free_page_idx;
-
-        let mut buffer = [0; PAGE_SIZE];
-        buffer.clone_from_slice(page.content());
+        let stored_page_id = header_page_id(&buffer);
 
         let mut index = Box::pin(BitmapPage {
             page_id,
             first_managed_page_id,
             last_managed_page_id,
+            width,
             current_first_free_page_idx,
             first_free_page_idx,
             free_page_count,
+            generation,
+            dirty: stored_page_id != page_id,
             buffer,
         });
-        index.free(page.page_id());
+        if stored_page_id != page_id {
+            index.free(stored_page_id);
+        }
 
-        index
+        Ok(index)
     }
 
 
-    pub fn allocate(&mut self, mut f: impl FnMut(u32) -> bool) -> Option<u32> {
+    pub fn allocate(&mut self, mut f: impl FnMut(u64) -> bool) -> Option<u64> {
         let start_page = self.first_managed_page_id;
-        let mut filter = |x: u16| f(start_page + x as u32);
+        let mut filter = |x: u16| f(start_page + x as u64);
         let (current_idx, page) = match self.bitmap().find_clear_filtered(self.current_first_free_page_idx, &mut filter) {
-            Some(idx) => (idx, Some(self.first_managed_page_id + idx as u32)),
+            Some(idx) => (idx, Some(self.first_managed_page_id + idx as u64)),
             None => (0xFFFF, None)
         };
 
         self.current_first_free_page_idx = current_idx;
-        page.map(|page_id| {
+        page.inspect(|&page_id| {
             self.mark_used(page_id, &mut filter);
-            page_id
         })
     }
 
 
-    fn mark_used(&mut self, page_id: u32, f: impl FnMut(u16) -> bool) -> bool {
+    // unlike allocate, a filtered out scan keeps the cursor so a retry with another filter sees the same pages
+    pub fn allocate_with_reason(&mut self, mut f: impl FnMut(u64) -> bool) -> std::result::Result<u64, AllocError> {
+        if self.free_page_count == 0 {
+            return Err(AllocError::Full);
+        }
+        let start_page = self.first_managed_page_id;
+        let mut scanned = 0u16;
+        let found = self.bitmap().find_clear_filtered(self.current_first_free_page_idx, |x| {
+            scanned += 1;
+            f(start_page + x as u64)
+        });
+
+        match found {
+            Some(idx) => {
+                let page_id = self.page_for(idx);
+                self.current_first_free_page_idx = idx;
+                self.mark_used(page_id, |_| true);
+                Ok(page_id)
+            }
+            None => Err(AllocError::AllFiltered { scanned }),
+        }
+    }
+
+    // the budget is the number of bitmap bytes scanned, fully used bytes included
+    pub fn allocate_with_budget(&mut self, budget: u32, f: impl FnMut(u64) -> bool) -> AllocOutcome {
+        let mut budget = budget;
+        self.allocate_within(&mut budget, f)
+    }
+
+    pub(crate) fn allocate_within(&mut self, budget: &mut u32, mut f: impl FnMut(u64) -> bool) -> AllocOutcome {
+        let start_page = self.first_managed_page_id;
+        let scan = self.bitmap().find_clear_budgeted(self.current_first_free_page_idx, budget, |x| f(start_page + x as u64));
+
+        match scan {
+            Scan::Found(idx) => {
+                let page_id = self.page_for(idx);
+                self.current_first_free_page_idx = idx;
+                self.mark_used(page_id, |_| true);
+                AllocOutcome::Allocated(page_id)
+            }
+            Scan::OutOfBudget => AllocOutcome::BudgetExhausted,
+            Scan::End => {
+                self.current_first_free_page_idx = 0xFFFF;
+                AllocOutcome::Full
+            }
+        }
+    }
+
+    pub fn reserve(&mut self, pages: Range<u64>) -> bool {
+        let in_range = pages.is_empty() || (self.contains(pages.start) && self.contains(pages.end - 1));
+        if in_range {
+            for page_id in pages {
+                self.mark_used(page_id, |_| true);
+            }
+        }
+        in_range
+    }
+
+    pub fn allocate_contiguous(&mut self, count: u16, f: impl FnMut(u64) -> bool) -> Option<u64> {
+        self.allocate_contiguous_aligned(count, 1, f)
+    }
+
+    pub fn allocate_contiguous_aligned(&mut self, count: u16, align: u16, mut f: impl FnMut(u64) -> bool) -> Option<u64> {
+        if count == 0 || align == 0 || count > self.free_page_count {
+            return None;
+        }
+
+        let (count, align) = (count as usize, align as usize);
+        let mut start = 0;
+        while start + count <= self.managed_page_count() as usize {
+            let blocked = (start..start + count)
+                .find(|idx| self.bitmap().is_set(*idx as u16) || !f(self.page_for(*idx as u16)));
+            match blocked {
+                Some(idx) => start = (idx / align + 1) * align,
+                None => {
+                    for idx in start..start + count {
+                        self.mark_used(self.page_for(idx as u16), |_| true);
+                    }
+                    return Some(self.page_for(start as u16));
+                }
+            }
+        }
+        None
+    }
+
+    fn mark_used(&mut self, page_id: u64, f: impl FnMut(u16) -> bool) -> bool {
         let offset = page_id - self.first_managed_page_id;
         let changed = self.bitmap_mut().set(offset as u16);
         if changed {
             self.free_page_count -= 1;
+            self.dirty = true;
             if page_id == self.page_for(self.current_first_free_page_idx) {
                 let next = self.bitmap().find_clear_filtered(self.current_first_free_page_idx + 1, f).unwrap_or(0xFFFF);
                 self.current_first_free_page_idx = next;
@@ -121,12 +357,111 @@ impl<'a> BitmapPage {
         changed
     }
 
-    fn page_for(&self, index: u16) -> u32 {
-        self.first_managed_page_id + index as u32
+    pub fn allocated_iter(&self) -> impl Iterator<Item = u64> + '_ {
+        let first_managed_page_id = self.first_managed_page_id;
+        self.bitmap().iter().enumerate()
+            .filter(|(_, byte)| **byte != 0)
+            .flat_map(move |(byte_index, byte)| {
+                let mut bits = *byte;
+                std::iter::from_fn(move || {
+                    if bits == 0 {
+                        return None;
+                    }
+                    let bit = bits.trailing_zeros();
+                    bits &= bits - 1;
+                    Some(first_managed_page_id + ((byte_index as u64) << 3) + bit as u64)
+                })
+            })
+    }
+
+    pub fn allocated_pages_rev(&self) -> impl Iterator<Item = u64> + '_ {
+        let first_managed_page_id = self.first_managed_page_id;
+        self.bitmap().iter().enumerate().rev()
+            .filter(|(_, byte)| **byte != 0)
+            .flat_map(move |(byte_index, byte)| {
+                let mut bits = *byte;
+                std::iter::from_fn(move || {
+                    if bits == 0 {
+                        return None;
+                    }
+                    let bit = 7 - bits.leading_zeros();
+                    bits &= !(1 << bit);
+                    Some(first_managed_page_id + ((byte_index as u64) << 3) + bit as u64)
+                })
+            })
+    }
+
+    pub fn validate_free_hint(&self) -> FreeHintStatus {
+        let hint = self.first_free_page_idx;
+        if hint == 0xFFFF {
+            FreeHintStatus::Ok
+        } else if hint >= self.managed_page_count() {
+            FreeHintStatus::OutOfRange
+        } else if self.bitmap().is_set(hint) {
+            FreeHintStatus::PointsAtUsed
+        } else {
+            FreeHintStatus::Ok
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let used_pages = self.bitmap().iter().map(|byte| byte.count_ones()).sum::<u32>();
+        self.managed_page_count() as u32 - used_pages == self.free_page_count as u32
+    }
+
+    pub(crate) fn last_allocated_page(&self) -> Option<u64> {
+        let (byte_index, byte) = self.bitmap().iter().enumerate().rev().find(|(_, byte)| **byte != 0)?;
+        let bit = 7 - byte.leading_zeros() as u16;
+        Some(self.page_for(((byte_index as u16) << 3) + bit))
+    }
+
+    pub fn runs(&self) -> impl Iterator<Item = Run> + '_ {
+        let bitmap = self.bitmap();
+        let end = self.managed_page_count() as usize;
+        let mut idx = 0;
+        std::iter::from_fn(move || {
+            if idx >= end {
+                return None;
+            }
+            let start = idx;
+            let allocated = bitmap.is_set(start as u16);
+            let uniform = if allocated { 0xFF } else { 0x00 };
+            while idx < end {
+                if idx & 0x07 == 0 && bitmap[idx >> 3] == uniform {
+                    idx += 8;
+                } else if bitmap.is_set(idx as u16) == allocated {
+                    idx += 1;
+                } else {
+                    break;
+                }
+            }
+            Some(Run {
+                start_page_id: self.page_for(start as u16),
+                length: (idx - start) as u32,
+                allocated,
+            })
+        })
+    }
+
+    pub fn largest_free_run(&self) -> u16 {
+        let managed_end = self.last_managed_page_id.saturating_add(1);
+        self.runs()
+            .filter(|run| !run.allocated)
+            .map(|run| (run.start_page_id + run.length as u64).min(managed_end).saturating_sub(run.start_page_id))
+            .max()
+            .unwrap_or(0) as u16
+    }
+
+    pub fn nth_allocated_page(&self, n: u32) -> Option<u64> {
+        self.bitmap().select(n, true).map(|index| self.page_for(index))
+    }
+
+    fn page_for(&self, index: u16) -> u64 {
+        self.first_managed_page_id + index as u64
     }
 
 
-    pub fn free(&mut self, page_id: u32) -> bool {
+    pub fn free(&mut self, page_id: u64) -> bool {
         let in_range = self.contains(page_id);
         if in_range {
             self.mark_free(page_id);
@@ -134,10 +469,34 @@ impl<'a> BitmapPage {
         in_range
     }
 
-    fn mark_free(&mut self, page_id: u32) {
+    pub fn free_range(&mut self, start_page_id: u64, count: u16) -> bool {
+        if count == 0 {
+            return true;
+        }
+        let end_page_id = match start_page_id.checked_add(count as u64 - 1) {
+            Some(end_page_id) => end_page_id,
+            None => return false,
+        };
+        if !self.contains(start_page_id) || !self.contains(end_page_id) {
+            return false;
+        }
+
+        let start_idx = (start_page_id - self.first_managed_page_id) as u16;
+        let bitmap = self.bitmap_mut();
+        let cleared = (start_idx..start_idx + count).filter(|idx| bitmap.clear(*idx)).count();
+        self.free_page_count += cleared as u16;
+        self.dirty |= cleared > 0;
+        if cleared > 0 && start_idx < self.first_free_page_idx {
+            self.first_free_page_idx = start_idx;
+        }
+        true
+    }
+
+    fn mark_free(&mut self, page_id: u64) {
         let offset = page_id - self.first_managed_page_id;
         if self.bitmap_mut().clear(offset as u16) {
             self.free_page_count += 1;
+            self.dirty = true;
             if page_id < self.page_for(self.first_free_page_idx) {
                 self.first_free_page_idx = (page_id - self.first_managed_page_id) as u16
             }
@@ -146,65 +505,127 @@ impl<'a> BitmapPage {
 
 
     fn bitmap(&'a self) -> &'a [u8] {
-        &self.buffer[BITMAP_HEADER_SIZE..PAGE_SIZE]
+        &self.buffer[bitmap_header_size(self.width)..bitmap_end(self.buffer.len(), self.width)]
     }
 
     fn bitmap_mut(&'a mut self) -> &'a mut [u8] {
-        &mut self.buffer[BITMAP_HEADER_SIZE..PAGE_SIZE]
+        let end = bitmap_end(self.buffer.len(), self.width);
+        &mut self.buffer[bitmap_header_size(self.width)..end]
     }
 
 
-    pub fn contains(&self, page_id: u32) -> bool {
+    pub fn is_allocated(&self, page_id: u64) -> Option<bool> {
+        if !self.contains(page_id) {
+            return None;
+        }
+        Some(self.bitmap().is_set((page_id - self.first_managed_page_id) as u16))
+    }
+
+    pub fn contains(&self, page_id: u64) -> bool {
         page_id >= self.first_managed_page_id && page_id <= self.last_managed_page_id
     }
 
 
     pub fn persist(&mut self, store: &mut PageStore) -> Result<()> {
+        self.generation = self.generation.wrapping_add(1);
         self.update_header();
 
-        store.write_page(self.page_id as usize, &self.buffer)
+        store.write_page(self.page_id as usize, &self.buffer)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn persist_to(&mut self, store: &mut PageStore, target_page_id: u64) -> Result<()> {
+        self.page_id = target_page_id;
+        self.persist(store)
     }
 
     fn update_header(&mut self) {
-        put_u32(&mut self.buffer, 0, self.page_id);
-        put_u32(&mut self.buffer, 4, PageType::Bitmap as u32);
-        put_u32(&mut self.buffer, 8, self.first_managed_page_id);
-        put_u16(&mut self.buffer, 12, self.free_page_count);
-        put_u16(&mut self.buffer, 14, self.first_free_page_idx);
+        let prefix = header_prefix_size(self.width);
+        put_header_prefix(&mut self.buffer, self.width.bitmap_type(), self.width, self.page_id, self.first_managed_page_id);
+        put_u16(&mut self.buffer, prefix, self.free_page_count);
+        put_u16(&mut self.buffer, prefix + 2, self.first_free_page_idx);
+        put_sentinel(&mut self.buffer, bitmap_generation_offset(self.width), self.generation);
+    }
+}
+
+fn bitmap_end(page_size: usize, width: PageIdWidth) -> usize {
+    bitmap_header_size(width) + bitmap_page_count(page_size, width) as usize / 8
+}
+
+fn last_managed_page_id(first_managed_page_id: u64, page_size: usize, width: PageIdWidth) -> Result<u64> {
+    if first_managed_page_id > max_first_managed_page_id(page_size, width) {
+        return invalid_input(
+            format!("invalid bitmap, managed pages from {} run beyond the last usable page {}", first_managed_page_id, width.max_page_id())
+        );
     }
+    Ok(first_managed_page_id + bitmap_page_count(page_size, width) as u64 - 1)
+}
+
+fn checked_first_managed_page_id(buffer: &[u8]) -> Result<u64> {
+    let width = PageIdWidth::of(buffer);
+    let first_managed_page_id = header_first_managed_page_id(buffer);
+    if first_managed_page_id > max_first_managed_page_id(buffer.len(), width) {
+        return invalid_data(
+            format!("corrupt bitmap page, managed pages from {} run beyond the last usable page {}", first_managed_page_id, width.max_page_id())
+        );
+    }
+    Ok(first_managed_page_id)
 }
 
 pub trait BitmapHeader {
-    fn page_id(&self) -> u32;
-    fn first_managed_page_id(&self) -> u32;
+    fn page_id(&self) -> u64;
+    fn first_managed_page_id(&self) -> u64;
     fn free_page_count(&self) -> u16;
     fn first_free_page_index(&self) -> u16;
 }
 
 impl BitmapHeader for MemoryPage {
-    fn page_id(&self) -> u32 {
-        self.get_u32(0)
+    fn page_id(&self) -> u64 {
+        header_page_id(self.content())
     }
 
-    fn first_managed_page_id(&self) -> u32 {
-        self.get_u32(8)
+    fn first_managed_page_id(&self) -> u64 {
+        header_first_managed_page_id(self.content())
     }
 
     fn free_page_count(&self) -> u16 {
-        self.get_u16(12)
+        self.get_u16(header_prefix_size(PageIdWidth::of(self.content())))
     }
 
     fn first_free_page_index(&self) -> u16 {
-        self.get_u16(14)
+        self.get_u16(header_prefix_size(PageIdWidth::of(self.content())) + 2)
+    }
+}
+
+impl BitmapHeader for PageHeaderSnapshot {
+    fn page_id(&self) -> u64 {
+        header_page_id(self.bytes())
+    }
+
+    fn first_managed_page_id(&self) -> u64 {
+        header_first_managed_page_id(self.bytes())
+    }
+
+    fn free_page_count(&self) -> u16 {
+        self.get_u16(header_prefix_size(PageIdWidth::of(self.bytes())))
+    }
+
+    fn first_free_page_index(&self) -> u16 {
+        self.get_u16(header_prefix_size(PageIdWidth::of(self.bytes())) + 2)
     }
 }
 
 impl BitmapHeader for Pin<Box<BitmapPage>> {
-    fn page_id(&self) -> u32 {
+    fn page_id(&self) -> u64 {
         self.page_id
     }
 
-    fn first_managed_page_id(&self) -> u32 {
+    fn first_managed_page_id(&self) -> u64 {
         self.first_managed_page_id
     }
 
@@ -218,11 +639,11 @@ impl BitmapHeader for Pin<Box<BitmapPage>> {
 }
 
 impl BitmapHeader for &Pin<Box<BitmapPage>> {
-    fn page_id(&self) -> u32 {
+    fn page_id(&self) -> u64 {
         self.page_id
     }
 
-    fn first_managed_page_id(&self) -> u32 {
+    fn first_managed_page_id(&self) -> u64 {
         self.first_managed_page_id
     }
 
@@ -235,12 +656,23 @@ impl BitmapHeader for &Pin<Box<BitmapPage>> {
     }
 }
 
+enum Scan {
+    Found(u16),
+    End,
+    OutOfBudget,
+}
+
 trait Bitmap {
     fn find_clear_filtered(&self, offset: u16, f: impl FnMut(u16) -> bool) -> Option<u16>;
+    fn find_clear_budgeted(&self, offset: u16, budget: &mut u32, f: impl FnMut(u16) -> bool) -> Scan;
 
     fn set(&mut self, index: u16) -> bool;
     fn clear(&mut self, index: u16) -> bool;
 
+    fn is_set(&self, index: u16) -> bool;
+
+    fn select(&self, n: u32, set: bool) -> Option<u16>;
+
     fn indices(&self, index: u16) -> (usize, u8) {
         let byte_index = index >> 3;
         let bit: u8 = (1 << (index & 0x07)) as u8;
@@ -258,7 +690,7 @@ impl Bitmap for [u8] {
 
         let byte = self[byte_start_index];
         if byte != 0xFF {
-            for bit in (offset & 0x07)..=7 as u16 {
+            for bit in (offset & 0x07)..=7 {
                 let mask = (1 << bit) as u8;
                 if byte & mask == 0 {
                     let candidate = ((byte_start_index as u16) << 3) + bit;
@@ -271,7 +703,7 @@ impl Bitmap for [u8] {
 
         for (byte_index, byte) in self[byte_start_index+1..].iter().enumerate() {
             if *byte != 0xFF {
-                for bit in 0..=7 as u16 {
+                for bit in 0..=7u16 {
                     let mask = (1 << bit) as u8;
                     if *byte & mask == 0 {
                         let candidate = (((byte_start_index + byte_index + 1) as u16) << 3) + bit;
@@ -285,6 +717,55 @@ impl Bitmap for [u8] {
         None
     }
 
+    fn find_clear_budgeted(&self, offset: u16, budget: &mut u32, mut f: impl FnMut(u16) -> bool) -> Scan {
+        let mut first_bit = offset & 0x07;
+        for (byte_index, &byte) in self.iter().enumerate().skip((offset >> 3) as usize) {
+            if *budget == 0 {
+                return Scan::OutOfBudget;
+            }
+            *budget -= 1;
+
+            if byte != 0xFF {
+                for bit in first_bit..=7 {
+                    let candidate = ((byte_index as u16) << 3) + bit;
+                    if byte & (1 << bit) as u8 == 0 && f(candidate) {
+                        return Scan::Found(candidate);
+                    }
+                }
+            }
+            first_bit = 0;
+        }
+        Scan::End
+    }
+
+    fn select(&self, n: u32, set: bool) -> Option<u16> {
+        let mut remaining = n;
+        for (word_index, chunk) in self.chunks(8).enumerate() {
+            let mut bytes = [0u8; 8];
+            for (target, byte) in bytes.iter_mut().zip(chunk) {
+                *target = if set { *byte } else { !*byte };
+            }
+            let mut word = u64::from_le_bytes(bytes);
+
+            let count = word.count_ones();
+            if remaining >= count {
+                remaining -= count;
+                continue;
+            }
+            for _ in 0..remaining {
+                word &= word - 1;
+            }
+            return Some(((word_index as u16) << 6) + word.trailing_zeros() as u16);
+        }
+        None
+    }
+
+    fn is_set(&self, index: u16) -> bool {
+        let (byte_index, bit) = self.indices(index);
+
+        self[byte_index] & bit == bit
+    }
+
     fn set(&mut self, index: u16) -> bool {
         let (byte_index, bit) = self.indices(index);
 
@@ -308,15 +789,6 @@ impl Bitmap for [u8] {
     }
 }
 
-fn put_u16(buffer: &mut [u8], idx: usize, value: u16) {
-    let bytes = value.to_le_bytes();
-    buffer[idx..idx + 2].clone_from_slice(&bytes);
-}
-
-fn put_u32(buffer: &mut [u8], idx: usize, value: u32) {
-    let bytes = value.to_le_bytes();
-    buffer[idx..idx + 4].clone_from_slice(&bytes);
-}
 
 
 #[cfg(test)]
@@ -1,19 +1,21 @@
 use crate::io::store::PageStore;
-use crate::io::bitmap::{BitmapPage, BITMAP_PAGE_COUNT, BitmapHeader};
-use crate::io::{PageType, PAGE_SIZE};
+use crate::io::bitmap::{put_u16, AllocError, AllocOutcome, Bitmap, BitmapPage, FreeHintStatus, Run, BITMAP_PAGE_COUNT, BitmapHeader, bitmap_header_size, bitmap_page_count, max_first_managed_page_id};
+use crate::io::{put_u32, PageIdWidth, PageType, MAX_PAGE_ID, PAGE_SIZE, PAGE_TRAILER_SIZE};
 use tempfile::tempfile;
+use std::io::ErrorKind;
 use std::pin::Pin;
 
 const TESTDB_MAX_SIZE: usize = 163840;
+const BITMAP_HEADER_SIZE: usize = bitmap_header_size(PageIdWidth::U32);
 
 #[inline(never)]
-fn unfiltered(_: u32) -> bool {
+fn unfiltered(_: u64) -> bool {
     true
 }
 
 #[test]
 fn new_allocator_for_new_database() {
-    let page = BitmapPage::new(2);
+    let page = BitmapPage::new(2).unwrap();
 
     assert_eq!(2, page.page_id);
     assert_eq!(2, page.first_managed_page_id);
@@ -21,22 +23,80 @@ fn new_allocator_for_new_database() {
     assert_eq!(BITMAP_PAGE_COUNT - 1, page.free_page_count);
 }
 
+#[test]
+fn external_allocator_does_not_consume_own_page() {
+    let page = BitmapPage::new_external(1, 2).unwrap();
+
+    assert_eq!(1, page.page_id);
+    assert_eq!(2, page.first_managed_page_id);
+    assert_eq!(0, page.first_free_page_idx);
+    assert_eq!(BITMAP_PAGE_COUNT, page.free_page_count);
+    assert_eq!(BITMAP_PAGE_COUNT - 1, BitmapPage::new(2).unwrap().free_page_count);
+}
+
+#[test]
+fn allocator_backed_by_page_inside_or_outside_its_range() {
+    let inside = BitmapPage::new_at(2, 5).unwrap();
+    assert_eq!(5, inside.page_id);
+    assert_eq!(0, inside.first_free_page_idx);
+    assert_eq!(BITMAP_PAGE_COUNT - 1, inside.free_page_count);
+    assert_eq!(Some(true), inside.is_allocated(5));
+
+    let outside = BitmapPage::new_at(2, 1).unwrap();
+    assert_eq!(1, outside.page_id);
+    assert_eq!(0, outside.first_free_page_idx);
+    assert_eq!(BITMAP_PAGE_COUNT, outside.free_page_count);
+}
+
+#[test]
+fn external_allocator_manages_from_page_zero() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new_external(2, 0).unwrap();
+
+    assert!(page.contains(0));
+    assert!(page.contains(BITMAP_PAGE_COUNT as u64 - 1));
+    assert!(!page.contains(BITMAP_PAGE_COUNT as u64));
+    assert_eq!(Some(0), page.allocate(unfiltered));
+    assert_eq!(Some(1), page.allocate(unfiltered));
+    assert_eq!(BITMAP_PAGE_COUNT - 2, page.free_page_count);
+
+    page.persist(&mut store).unwrap();
+    let memory_page = store.read_page(2).unwrap();
+    assert_eq!(2, memory_page.page_id());
+    assert_eq!(0, memory_page.first_managed_page_id());
+    assert_eq!(0x03, memory_page.content()[BITMAP_HEADER_SIZE]);
+}
+
+#[test]
+fn reset_allocator_allocates_like_new_one() {
+    let mut page = full_bitmap();
+    page.reset(2 + BITMAP_PAGE_COUNT as u64).unwrap();
+    let mut fresh = BitmapPage::new(2 + BITMAP_PAGE_COUNT as u64).unwrap();
+
+    assert_eq!(fresh.page_id, page.page_id);
+    assert_eq!(fresh.free_page_count, page.free_page_count);
+    for _ in 0..100 {
+        assert_eq!(fresh.allocate(unfiltered), page.allocate(unfiltered));
+    }
+    assert_eq!(fresh.bits(), page.bits());
+}
+
 #[test]
 fn allocator_allocates_pages_monotonically_increasing() {
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
 
     assert_eq!(Some(3), page.allocate(unfiltered));
     assert_eq!(Some(4), page.allocate(unfiltered));
-    assert_eq!(true, page.free(3));
+    assert!(page.free(3));
     assert_eq!(Some(5), page.allocate(unfiltered));
 }
 
 
 #[test]
 fn allocator_allocates_pages_monotonically_increasing_and_skips_used_pages() {
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
 
-    let f = |x: u32| x != 4 && x != 5 && x != 7 && x != 16;
+    let f = |x: u64| x != 4 && x != 5 && x != 7 && x != 16;
 
     assert_eq!(Some(3), page.allocate(f));
     assert_eq!(Some(6), page.allocate(f));
@@ -52,14 +112,392 @@ fn allocator_allocates_pages_monotonically_increasing_and_skips_used_pages() {
     assert_eq!(Some(18), page.allocate(f));
 }
 
+#[test]
+fn reserved_pages_are_never_allocated() {
+    let mut page = BitmapPage::new(2).unwrap();
+
+    assert!(page.reserve(10..50));
+    assert_eq!(BITMAP_PAGE_COUNT - 41, page.free_page_count);
+
+    for _ in 0..200 {
+        let page_id = page.allocate(unfiltered).unwrap();
+        assert!(!(10..50).contains(&page_id));
+    }
+    for page_id in 10..50u32 {
+        let (byte, bit) = page.bitmap().indices((page_id - 2) as u16);
+        assert_eq!(bit, page.bitmap()[byte] & bit);
+    }
+}
+
+#[test]
+fn cannot_reserve_outside_managed_range() {
+    let mut page = BitmapPage::new(2).unwrap();
+
+    assert!(!page.reserve(0..10));
+    assert_eq!(BITMAP_PAGE_COUNT - 1, page.free_page_count);
+}
+
+#[test]
+fn allocate_within_budget() {
+    let mut page = BitmapPage::new(2).unwrap();
+
+    assert_eq!(AllocOutcome::Allocated(3), page.allocate_with_budget(1, unfiltered));
+    assert_eq!(AllocOutcome::Allocated(6), page.allocate_with_budget(4, |x| x > 5));
+    assert_eq!(AllocOutcome::Allocated(7), page.allocate_with_budget(4, unfiltered));
+}
+
+#[test]
+fn allocate_with_reason_tells_full_from_filtered() {
+    let mut page = BitmapPage::new(2).unwrap();
+
+    assert_eq!(Err(AllocError::AllFiltered { scanned: BITMAP_PAGE_COUNT - 1 }), page.allocate_with_reason(|_| false));
+    assert_eq!(Ok(3), page.allocate_with_reason(unfiltered));
+    assert_eq!(Ok(6), page.allocate_with_reason(|x| x > 5));
+    assert_eq!(Err(AllocError::Full), full_bitmap().allocate_with_reason(unfiltered));
+}
+
+#[test]
+fn allocate_gives_up_when_budget_is_exhausted() {
+    let mut page = BitmapPage::new(2).unwrap();
+    let mut examined = 0;
+
+    let outcome = page.allocate_with_budget(10, |_| {
+        examined += 1;
+        false
+    });
+
+    assert_eq!(AllocOutcome::BudgetExhausted, outcome);
+    // ten bytes hold 80 pages, the bitmap's own page is already used
+    assert_eq!(79, examined);
+    assert_eq!(BITMAP_PAGE_COUNT - 1, page.free_page_count);
+}
+
+#[test]
+fn budget_is_charged_for_fully_used_bytes() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(3..2 + 20 * 8));
+    page.current_first_free_page_idx = 1;
+    let mut examined = 0;
+
+    let outcome = page.allocate_with_budget(20, |_| {
+        examined += 1;
+        true
+    });
+
+    assert_eq!(AllocOutcome::BudgetExhausted, outcome);
+    assert_eq!(0, examined);
+    assert_eq!(AllocOutcome::Allocated(2 + 20 * 8), page.allocate_with_budget(21, unfiltered));
+}
+
+#[test]
+fn allocate_with_budget_on_full_page() {
+    let mut page = full_bitmap();
+
+    assert_eq!(AllocOutcome::Full, page.allocate_with_budget(10, unfiltered));
+}
+
+#[test]
+fn iterates_allocated_pages_in_descending_order() {
+    let mut page = BitmapPage::new(2).unwrap();
+    for wanted in [3, 7, 18].iter() {
+        assert!(page.reserve(*wanted..*wanted + 1));
+    }
+
+    assert_eq!(vec![18, 7, 3, 2], page.allocated_pages_rev().collect::<Vec<_>>());
+}
+
+#[test]
+fn iterates_allocated_pages_in_ascending_order() {
+    let mut page = BitmapPage::new(2).unwrap();
+    for _ in 0..5 {
+        page.allocate(|_| true).unwrap();
+    }
+    assert!(page.free(4));
+    assert!(page.free(6));
+    assert!(page.reserve(20..21));
+
+    assert_eq!(vec![2, 3, 5, 7, 20], page.allocated_iter().collect::<Vec<_>>());
+    assert_eq!(Some(1 + BITMAP_PAGE_COUNT as u64), full_bitmap().allocated_iter().last());
+}
+
+#[test]
+fn iterates_allocated_pages_up_to_last_managed_page() {
+    let page = full_bitmap();
+
+    let pages = page.allocated_pages_rev().collect::<Vec<_>>();
+    assert_eq!(BITMAP_PAGE_COUNT as usize, pages.len());
+    assert_eq!(Some(&(1 + BITMAP_PAGE_COUNT as u64)), pages.first());
+    assert_eq!(Some(&2), pages.last());
+}
+
+#[test]
+fn selects_nth_set_and_clear_bit() {
+    let mut bitmap = [0u8; 16];
+    for index in [1, 9, 70, 127].iter() {
+        bitmap.set(*index);
+    }
+
+    assert_eq!(Some(1), bitmap.select(0, true));
+    assert_eq!(Some(70), bitmap.select(2, true));
+    assert_eq!(Some(127), bitmap.select(3, true));
+    assert_eq!(None, bitmap.select(4, true));
+
+    assert_eq!(Some(0), bitmap.select(0, false));
+    assert_eq!(Some(2), bitmap.select(1, false));
+    assert_eq!(Some(126), bitmap.select(123, false));
+    assert_eq!(None, bitmap.select(124, false));
+}
+
+#[test]
+fn finds_nth_allocated_page() {
+    let mut page = BitmapPage::new(2).unwrap();
+    for wanted in [3, 7, 18].iter() {
+        assert!(page.reserve(*wanted..*wanted + 1));
+    }
+
+    assert_eq!(Some(2), page.nth_allocated_page(0));
+    assert_eq!(Some(7), page.nth_allocated_page(2));
+    assert_eq!(None, page.nth_allocated_page(4));
+}
+
+#[test]
+fn allocates_contiguous_run() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(5..6));
+    assert!(page.reserve(9..10));
+
+    assert_eq!(Some(10), page.allocate_contiguous(4, unfiltered));
+    assert_eq!(Some(3), page.allocate_contiguous(2, unfiltered));
+    assert_eq!(Some(14), page.allocate_contiguous(2, |page_id| page_id != 7));
+    assert_eq!(BITMAP_PAGE_COUNT - 11, page.free_page_count);
+    assert_eq!(None, page.allocate_contiguous(BITMAP_PAGE_COUNT, unfiltered));
+}
+
+#[test]
+fn finds_free_bit_7_of_starting_byte() {
+    let bitmap = [0xFF, 0x7F, 0xFF];
+    assert_eq!(Some(15), bitmap.find_clear_filtered(9, |_| true));
+    assert_eq!(Some(15), bitmap.find_clear_filtered(15, |_| true));
+
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(3..17));
+    assert!(page.reserve(18..2 + BITMAP_PAGE_COUNT as u64));
+    assert_eq!(Some(17), page.allocate(unfiltered));
+    assert_eq!(None, page.allocate(unfiltered));
+}
+
+#[test]
+fn reports_whether_page_is_allocated() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(7..8));
+    page.persist(&mut store).unwrap();
+
+    let loaded = BitmapPage::load_into(&store.read_page(2).unwrap(), 2).unwrap();
+    assert_eq!(Some(true), loaded.is_allocated(2));
+    assert_eq!(Some(false), loaded.is_allocated(3));
+    assert_eq!(Some(true), loaded.is_allocated(7));
+    assert_eq!(Some(false), loaded.is_allocated(1 + BITMAP_PAGE_COUNT as u64));
+    assert_eq!(None, loaded.is_allocated(1));
+    assert_eq!(None, loaded.is_allocated(2 + BITMAP_PAGE_COUNT as u64));
+}
+
+#[test]
+fn frees_range_of_pages() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(3..20));
+    assert!(page.free(10));
+    assert_eq!(8, page.first_free_page_idx);
+
+    assert!(page.free_range(5, 8));
+    assert_eq!(BITMAP_PAGE_COUNT - 18 + 8, page.free_page_count);
+    assert_eq!(3, page.first_free_page_idx);
+    assert_eq!(Some(5), page.allocate_contiguous(8, unfiltered));
+
+    assert!(!page.free_range(1, 2));
+    assert!(!page.free_range(1 + BITMAP_PAGE_COUNT as u64, 2));
+    assert_eq!(BITMAP_PAGE_COUNT - 18, page.free_page_count);
+}
+
+#[test]
+fn allocates_aligned_contiguous_run() {
+    let mut page = BitmapPage::new(2).unwrap();
+
+    let first = page.allocate_contiguous_aligned(3, 8, |_| true).unwrap();
+    assert_eq!(10, first);
+    assert_eq!(0, (first - page.first_managed_page_id) % 8);
+    assert_eq!(BITMAP_PAGE_COUNT - 4, page.free_page_count);
+
+    assert!(page.reserve(19..20));
+    assert_eq!(Some(34), page.allocate_contiguous_aligned(4, 16, |_| true));
+    assert_eq!(None, page.allocate_contiguous_aligned(1, 8, |page_id| page_id > 2 + BITMAP_PAGE_COUNT as u64));
+}
+
+#[test]
+fn reports_runs_of_allocated_and_free_pages() {
+    let mut page = BitmapPage::new_external(1, 2).unwrap();
+    assert!(page.reserve(2..5));
+    assert!(page.reserve(10..12));
+    assert!(page.reserve(30..50));
+
+    let runs = page.runs().collect::<Vec<_>>();
+    assert_eq!(vec![
+        Run { start_page_id: 2, length: 3, allocated: true },
+        Run { start_page_id: 5, length: 5, allocated: false },
+        Run { start_page_id: 10, length: 2, allocated: true },
+        Run { start_page_id: 12, length: 18, allocated: false },
+        Run { start_page_id: 30, length: 20, allocated: true },
+        Run { start_page_id: 50, length: BITMAP_PAGE_COUNT as u32 - 48, allocated: false },
+    ], runs);
+    assert_eq!(BITMAP_PAGE_COUNT as u32, runs.iter().map(|run| run.length).sum::<u32>());
+}
+
+#[test]
+fn verifies_free_count_against_bits() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.verify());
+    page.allocate(|_| true).unwrap();
+    page.free_range(10, 5);
+    assert!(page.verify());
+    assert!(full_bitmap().verify());
+
+    page.free_page_count += 1;
+    assert!(!page.verify());
+}
+
+#[test]
+fn finds_largest_free_run() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert_eq!(BITMAP_PAGE_COUNT - 1, page.largest_free_run());
+
+    for page_id in (2..2 + BITMAP_PAGE_COUNT as u64).step_by(2) {
+        page.reserve(page_id..page_id + 1);
+    }
+    assert_eq!(1, page.largest_free_run());
+
+    // the odd neighbours on either side of a freed range are free as well
+    page.free_range(100, 7);
+    assert_eq!(9, page.largest_free_run());
+    page.free_range(300, 20);
+    assert_eq!(21, page.largest_free_run());
+
+    page.free_range(2 + BITMAP_PAGE_COUNT as u64 - 40, 40);
+    assert_eq!(41, page.largest_free_run());
+
+    assert_eq!(0, full_bitmap().largest_free_run());
+}
+
+#[test]
+fn manages_pages_up_to_max_page_id() {
+    let mut page = BitmapPage::new(max_first_managed_page_id(PAGE_SIZE, PageIdWidth::U32)).unwrap();
+    let max_page_id = MAX_PAGE_ID as u64;
+
+    assert!(page.contains(max_page_id));
+    assert!(!page.contains(max_page_id + 1));
+    assert!(page.reserve(max_page_id - 1..max_page_id + 1));
+    assert_eq!(Some(true), page.is_allocated(max_page_id));
+}
+
+#[test]
+fn cannot_create_bitmap_managing_sentinel_page() {
+    let first_managed_page_id = max_first_managed_page_id(PAGE_SIZE, PageIdWidth::U32) + 1;
+
+    assert_eq!(ErrorKind::InvalidInput, BitmapPage::new(first_managed_page_id).err().unwrap().kind());
+    assert_eq!(ErrorKind::InvalidInput, BitmapPage::new_external(1, first_managed_page_id).err().unwrap().kind());
+
+    let mut page = BitmapPage::new(2).unwrap();
+    assert_eq!(ErrorKind::InvalidInput, page.reset(first_managed_page_id).unwrap_err().kind());
+    assert!(page.contains(2));
+}
+
+#[test]
+fn bitmap_size_follows_page_size() {
+    assert_eq!(BITMAP_PAGE_COUNT, bitmap_page_count(PAGE_SIZE, PageIdWidth::U32));
+    assert_eq!(65344, bitmap_page_count(8192, PageIdWidth::U32));
+    assert_eq!(0xFFF8, bitmap_page_count(16384, PageIdWidth::U32));
+
+    let page_size = 16384;
+    let file = tempfile().unwrap();
+    let mut store = PageStore::with_page_size(file, 4 * page_size, page_size).unwrap();
+    let mut page = BitmapPage::with_page_size(2, 2, page_size).unwrap();
+    assert_eq!(0xFFF8 - 1, page.free_page_count());
+    assert!(page.contains(2 + 0xFFF8 - 1));
+    assert!(!page.contains(2 + 0xFFF8));
+
+    page.reserve(3..0xFFF8);
+    page.persist_to(&mut store, 1).unwrap();
+    let loaded = BitmapPage::load_into(&store.read_page(1).unwrap(), 1).unwrap();
+    assert_eq!(page_size, loaded.page_size());
+    assert_eq!(Some(true), loaded.is_allocated(0xFFF8 - 1));
+    assert_eq!(Some(false), loaded.is_allocated(0xFFF8));
+    assert!(loaded.verify());
+}
+
+#[test]
+fn manages_pages_beyond_max_page_id_with_u64_page_ids() {
+    let first_managed_page_id = 5_000_000_000;
+    let mut store = temporary_store();
+    let mut page = BitmapPage::with_layout(first_managed_page_id, 2, PAGE_SIZE, PageIdWidth::U64).unwrap();
+    assert_eq!(bitmap_page_count(PAGE_SIZE, PageIdWidth::U64), page.free_page_count());
+    assert_eq!(Some(first_managed_page_id), page.allocate(unfiltered));
+    assert!(page.reserve(first_managed_page_id + 10..first_managed_page_id + 12));
+    page.persist(&mut store).unwrap();
+
+    let memory_page = store.read_page(2).unwrap();
+    assert_eq!(Some(PageType::WideBitmap), memory_page.typed());
+    assert_eq!(2, BitmapHeader::page_id(&memory_page));
+    assert_eq!(first_managed_page_id, memory_page.first_managed_page_id());
+
+    let loaded = BitmapPage::load_into(&memory_page, 2).unwrap();
+    assert_eq!(PageIdWidth::U64, loaded.id_width());
+    assert_eq!(Some(true), loaded.is_allocated(first_managed_page_id + 11));
+    assert_eq!(Some(false), loaded.is_allocated(first_managed_page_id + 12));
+    assert!(loaded.verify());
+
+    assert_eq!(ErrorKind::InvalidInput, BitmapPage::with_layout(first_managed_page_id, 2, PAGE_SIZE, PageIdWidth::U32).err().unwrap().kind());
+}
+
+#[test]
+fn cannot_create_bitmap_for_unsupported_page_size() {
+    assert_eq!(ErrorKind::InvalidInput, BitmapPage::with_page_size(2, 2, 256).err().unwrap().kind());
+    assert_eq!(ErrorKind::InvalidInput, BitmapPage::with_page_size(2, 2, 6000).err().unwrap().kind());
+}
+
+#[test]
+fn cannot_load_bitmap_managing_sentinel_page() {
+    let mut store = temporary_store();
+    BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+    store.write_page_range(2, 8, &(max_first_managed_page_id(PAGE_SIZE, PageIdWidth::U32) + 1).to_le_bytes()).unwrap();
+
+    let memory_page = store.read_page(2).unwrap();
+    match BitmapPage::load_into(&memory_page, 2) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded a bitmap managing the sentinel page id")
+    }
+}
+
+#[test]
+fn validates_free_hint() {
+    let mut page = BitmapPage::new(2).unwrap();
+    assert_eq!(FreeHintStatus::Ok, page.validate_free_hint());
+
+    page.first_free_page_idx = 0;
+    assert_eq!(FreeHintStatus::PointsAtUsed, page.validate_free_hint());
+
+    page.first_free_page_idx = BITMAP_PAGE_COUNT;
+    assert_eq!(FreeHintStatus::OutOfRange, page.validate_free_hint());
+
+    assert_eq!(FreeHintStatus::Ok, full_bitmap().validate_free_hint());
+}
+
 #[test]
 fn searches_through_all_bits_for_next_free_page() {
     let mut index = full_bitmap();
-    index.free(2 + BITMAP_PAGE_COUNT as u32 - 1);
+    index.free(2 + BITMAP_PAGE_COUNT as u64 - 1);
     index.current_first_free_page_idx = 0;
 
     let option = index.allocate(unfiltered);
-    assert_eq!(Some(2 + BITMAP_PAGE_COUNT as u32 -1), option)
+    assert_eq!(Some(2 + BITMAP_PAGE_COUNT as u64 -1), option)
 }
 
 #[test]
@@ -73,17 +511,33 @@ fn cannot_allocate_on_full_page() {
 #[test]
 fn persist_writes_correct_index() {
     let mut store = temporary_store();
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
 
     page.persist(&mut store).unwrap();
 
     let memory_page = store.read_page(2).unwrap();
     assert_eq!(2, memory_page.page_id());
-    assert_eq!(PageType::Bitmap as u32, memory_page.page_type());
+    assert_eq!(Some(PageType::Bitmap), memory_page.typed());
     assert_eq!(2, memory_page.first_managed_page_id());
     assert_eq!(BITMAP_PAGE_COUNT - 1, memory_page.free_page_count());
     assert_eq!(1, memory_page.first_free_page_index());
-    assert_eq!(0x01, memory_page.content()[16]);
+    assert_eq!(0x01, memory_page.content()[BITMAP_HEADER_SIZE]);
+}
+
+#[test]
+fn persist_to_writes_bitmap_at_target_page() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(5..7));
+
+    page.persist_to(&mut store, 30).unwrap();
+    assert_eq!(30, page.page_id);
+
+    let memory_page = store.read_page(30).unwrap();
+    assert_eq!(30, memory_page.page_id());
+    assert_eq!(2, memory_page.first_managed_page_id());
+    assert_eq!(BITMAP_PAGE_COUNT - 3, memory_page.free_page_count());
+    assert_eq!(0x19, memory_page.content()[BITMAP_HEADER_SIZE]);
 }
 
 #[test]
@@ -93,7 +547,7 @@ fn cannot_load_full_page() {
     full_bitmap().persist(&mut store).unwrap();
 
     let memory_page = store.read_page(2).unwrap();
-    let loaded = BitmapPage::load(&memory_page, |_| true);
+    let loaded = BitmapPage::load(&memory_page, |_| true).unwrap();
     assert!(loaded.is_none());
 }
 
@@ -102,11 +556,11 @@ fn cannot_load_almost_full_page() {
     let mut store = temporary_store();
 
     let mut index = full_bitmap();
-    index.free(2 + BITMAP_PAGE_COUNT as u32 - 1);
+    index.free(2 + BITMAP_PAGE_COUNT as u64 - 1);
     index.persist(&mut store).unwrap();
 
     let memory_page = store.read_page(2).unwrap();
-    let loaded = BitmapPage::load(&memory_page, |_| true);
+    let loaded = BitmapPage::load(&memory_page, |_| true).unwrap();
     assert!(loaded.is_none());
 }
 
@@ -114,18 +568,18 @@ fn cannot_load_almost_full_page() {
 fn cannot_load_empty_page_if_still_in_use() {
     let mut store = temporary_store();
 
-    let mut index = BitmapPage::new(2);
+    let mut index = BitmapPage::new(2).unwrap();
     index.persist(&mut store).unwrap();
 
     let memory_page = store.read_page(2).unwrap();
-    let loaded = BitmapPage::load(&memory_page, |_| false);
+    let loaded = BitmapPage::load(&memory_page, |_| false).unwrap();
     assert!(loaded.is_none());
 }
 
 #[test]
 fn load_viable_index() {
     let mut store = temporary_store();
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     page.allocate(unfiltered);
     page.allocate(unfiltered);
     page.free(3);
@@ -134,48 +588,94 @@ fn load_viable_index() {
 
     let memory_page = store.read_page(2).unwrap();
 
-    let mut new_index = BitmapPage::load(&memory_page, |x| x != 3).unwrap();
+    let mut new_index = BitmapPage::load(&memory_page, |x| x != 3).unwrap().unwrap();
     new_index.allocate(|x| x != 3);
     new_index.persist(&mut store).unwrap();
 
     let new_memory_page = store.read_page(5).unwrap();
     assert_eq!(5, new_memory_page.page_id());
-    assert_eq!(PageType::Bitmap as u32, new_memory_page.page_type());
+    assert_eq!(Some(PageType::Bitmap), new_memory_page.typed());
     assert_eq!(2, new_memory_page.get_u32(8)); // first_managed_page_id
     assert_eq!(BITMAP_PAGE_COUNT - 3, new_memory_page.get_u16(12)); // free page count
     assert_eq!(0, new_memory_page.get_u16(14)); // free page index
-    assert_eq!(0x1C, new_memory_page.content()[16]);
+    assert_eq!(0x1C, new_memory_page.content()[BITMAP_HEADER_SIZE]);
 }
 
 #[test]
 fn load_into_viable_index() {
     let mut store = temporary_store();
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     page.persist(&mut store).unwrap();
 
     let memory_page = store.read_page(2).unwrap();
 
-    let new_index = BitmapPage::load_into(&memory_page, 0);
+    let new_index = BitmapPage::load_into(&memory_page, 0).unwrap();
 
     assert_eq!(0, new_index.page_id());
     assert_eq!(2, new_index.first_managed_page_id);
     assert_eq!(BITMAP_PAGE_COUNT, new_index.free_page_count);
     assert_eq!(0, new_index.first_free_page_idx);
-    assert_eq!(0, new_index.buffer[16]);
+    assert_eq!(0, new_index.buffer[BITMAP_HEADER_SIZE]);
+}
+
+#[test]
+fn cannot_load_short_page() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+    page.persist(&mut store).unwrap();
+
+    let short_page = store.read_page(2).unwrap().truncated(PAGE_SIZE - 1);
+
+    match BitmapPage::load(&short_page, unfiltered) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded a short page")
+    }
+    match BitmapPage::load_into(&short_page, 0) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded a short page")
+    }
+}
+
+#[test]
+fn persist_increments_generation() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+
+    page.persist(&mut store).unwrap();
+    page.persist(&mut store).unwrap();
+
+    let memory_page = store.read_page(2).unwrap();
+    assert_eq!(2, memory_page.get_u32(16));
+    assert_eq!(2, memory_page.get_u32(PAGE_SIZE - PAGE_TRAILER_SIZE));
+    assert_eq!(2, BitmapPage::load_into(&memory_page, 2).unwrap().generation);
+}
+
+#[test]
+fn cannot_load_torn_page() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+    page.persist(&mut store).unwrap();
+
+    store.write_page_range(2, PAGE_SIZE - PAGE_TRAILER_SIZE, &[0xFF; PAGE_TRAILER_SIZE]).unwrap();
+
+    let memory_page = store.read_page(2).unwrap();
+    match BitmapPage::load(&memory_page, unfiltered) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded a torn page")
+    }
 }
 
 fn temporary_store() -> PageStore {
     let file = tempfile().unwrap();
-    let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
-    store
+    PageStore::new(file, TESTDB_MAX_SIZE).unwrap()
 }
 
 fn full_bitmap() -> Pin<Box<BitmapPage>> {
-    let mut index = BitmapPage::new(2);
+    let mut index = BitmapPage::new(2).unwrap();
     index.current_first_free_page_idx = 0xFFFF;
     index.first_free_page_idx = 0xFFFF;
     index.free_page_count = 0;
-    index.buffer = [0xFF; PAGE_SIZE];
+    index.buffer = vec![0xFF; PAGE_SIZE];
     index
 }
 
@@ -185,7 +685,7 @@ fn full_bitmap() -> Pin<Box<BitmapPage>> {
 
 #[test]
 fn bitmap_page_header() {
-    let page = BitmapPage::new(2);
+    let page = BitmapPage::new(2).unwrap();
 
     let header: &dyn BitmapHeader = &page;
 
@@ -198,7 +698,7 @@ fn bitmap_page_header() {
 
 #[test]
 fn bitmap_page_ref_header() {
-    let page = &BitmapPage::new(2);
+    let page = &BitmapPage::new(2).unwrap();
 
     let header: &dyn BitmapHeader = &page;
 
@@ -212,7 +712,7 @@ fn bitmap_page_ref_header() {
 fn memory_page_header() {
     let file = tempfile().unwrap();
     let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     page.persist(&mut store).unwrap();
 
     let new_memory_page = store.read_page(2).unwrap();
@@ -223,3 +723,43 @@ fn memory_page_header() {
     assert_eq!(2, header.first_managed_page_id());
     assert_eq!(1, header.first_free_page_index());
 }
+
+#[test]
+fn header_snapshot_header() {
+    let mut store = temporary_store();
+    let mut page = BitmapPage::new(2).unwrap();
+    page.persist(&mut store).unwrap();
+
+    let snapshot = store.read_header(2).unwrap();
+    let header: &dyn BitmapHeader = &snapshot;
+
+    assert_eq!(2, header.page_id());
+    assert_eq!(BITMAP_PAGE_COUNT - 1, header.free_page_count());
+    assert_eq!(2, header.first_managed_page_id());
+    assert_eq!(1, header.first_free_page_index());
+}
+
+
+#[test]
+fn put_helpers_agree_with_page_readers() {
+    let mut store = temporary_store();
+    let values: [u32; 5] = [0, 1, u16::MAX as u32, u32::MAX, 0x1234_5678];
+
+    let mut buffer = [0u8; PAGE_SIZE];
+    for (i, value) in values.iter().enumerate() {
+        put_u32(&mut buffer, i * 4, *value);
+        put_u16(&mut buffer, 20 + i * 2, *value as u16);
+    }
+    assert_eq!([0x78, 0x56, 0x34, 0x12], buffer[16..20]);
+    store.write_page(1, &buffer).unwrap();
+
+    let memory_page = store.read_page(1).unwrap();
+    let snapshot = store.read_header(1).unwrap();
+    for (i, value) in values.iter().enumerate() {
+        assert_eq!(*value, memory_page.get_u32(i * 4));
+        assert_eq!(*value, snapshot.get_u32(i * 4));
+        assert_eq!(*value, crate::io::index::get_u32(memory_page.content(), i * 4));
+        assert_eq!(*value as u16, memory_page.get_u16(20 + i * 2));
+        assert_eq!(*value as u16, snapshot.get_u16(20 + i * 2));
+    }
+}
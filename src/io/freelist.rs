@@ -0,0 +1,242 @@
+use std::io::Result;
+use std::pin::Pin;
+use crate::io::{PAGE_SIZE, PAGE_TRAILER_SIZE, PageType, check_page_size, expect_sized_page, invalid_data, put_sentinel, check_sentinel, put_u16, put_u32};
+use crate::io::store::{MemoryPage, PageStore};
+
+// page id 0..4, type 4..8, next page id 8..12, entry count 12..14, 2 bytes spare, generation 16..20
+const FREE_LIST_HEADER_SIZE: usize = 20;
+const FREE_LIST_GENERATION_OFFSET: usize = 16;
+const _: () = assert!(FREE_LIST_GENERATION_OFFSET + 4 <= FREE_LIST_HEADER_SIZE);
+const NO_NEXT: u32 = 0xFFFF_FFFF;
+
+pub struct FreeListPage {
+    page_id: u32,
+    next_page_id: u32,
+    len: u16,
+    generation: u32,
+    buffer: Vec<u8>,
+}
+
+impl FreeListPage {
+    pub fn new(page_id: u32) -> Pin<Box<FreeListPage>> {
+        FreeListPage::sized(page_id, PAGE_SIZE)
+    }
+
+    pub fn with_page_size(page_id: u32, page_size: usize) -> Result<Pin<Box<FreeListPage>>> {
+        check_page_size(page_size)?;
+        Ok(FreeListPage::sized(page_id, page_size))
+    }
+
+    fn sized(page_id: u32, page_size: usize) -> Pin<Box<FreeListPage>> {
+        Box::pin(FreeListPage {
+            page_id,
+            next_page_id: NO_NEXT,
+            len: 0,
+            generation: 0,
+            buffer: vec![0; page_size],
+        })
+    }
+
+    pub fn load(page: &MemoryPage) -> Result<Pin<Box<FreeListPage>>> {
+        let page_size = expect_sized_page(page.content())?.len();
+        let mut free_list = FreeListPage::sized(page.page_id() as u32, page_size);
+        free_list.load_entries(page)?;
+        Ok(free_list)
+    }
+
+    pub fn page_id(&self) -> u32 {
+        self.page_id
+    }
+
+    pub fn next_page_id(&self) -> Option<u32> {
+        if self.next_page_id == NO_NEXT {
+            None
+        } else {
+            Some(self.next_page_id)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn capacity(&self) -> usize {
+        free_list_capacity(self.buffer.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && self.next_page_id == NO_NEXT
+    }
+
+    // a full page spills its entries into the freed page, which becomes the next page of the chain
+    pub fn push(&mut self, page_id: u32, store: &mut PageStore) -> Result<()> {
+        if self.len as usize == self.capacity() {
+            self.write_header(page_id, self.generation.wrapping_add(1));
+            store.write_page(page_id as usize, &self.buffer)?;
+            self.next_page_id = page_id;
+            self.len = 0;
+            return Ok(());
+        }
+        put_u32(&mut self.buffer, entry_offset(self.len), page_id);
+        self.len += 1;
+        Ok(())
+    }
+
+    // popping past an empty page pulls in the next page of the chain and hands out that page itself
+    pub fn pop(&mut self, store: &PageStore) -> Result<Option<u32>> {
+        if self.len > 0 {
+            self.len -= 1;
+            let idx = entry_offset(self.len);
+            return Ok(Some(u32::from_le_bytes([self.buffer[idx], self.buffer[idx + 1], self.buffer[idx + 2], self.buffer[idx + 3]])));
+        }
+        let next_page_id = match self.next_page_id() {
+            Some(next_page_id) => next_page_id,
+            None => return Ok(None),
+        };
+        let page = store.read_page(next_page_id as usize)?;
+        self.load_entries(&page)?;
+        Ok(Some(next_page_id))
+    }
+
+    pub fn persist(&mut self, store: &mut PageStore) -> Result<()> {
+        self.generation = self.generation.wrapping_add(1);
+        self.write_header(self.page_id, self.generation);
+
+        Ok(store.write_page(self.page_id as usize, &self.buffer)?)
+    }
+
+    fn load_entries(&mut self, page: &MemoryPage) -> Result<()> {
+        let buffer = page.content();
+        if buffer.len() != self.buffer.len() {
+            return invalid_data(
+                format!("invalid page, expected {} bytes but got {}", self.buffer.len(), buffer.len())
+            );
+        }
+        let generation = check_sentinel(buffer, FREE_LIST_GENERATION_OFFSET)?;
+        if page.page_type() != PageType::FreeList as u32 {
+            return invalid_data(
+                format!("unexpected page type {} (expected {})", page.page_type(), PageType::FreeList as u32)
+            );
+        }
+        let len = page.get_u16(12);
+        if len as usize > self.capacity() {
+            return invalid_data(
+                format!("corrupt free list page, {} entries exceed capacity {}", len, self.capacity())
+            );
+        }
+
+        self.next_page_id = page.get_u32(8);
+        self.len = len;
+        self.generation = self.generation.max(generation);
+        self.buffer.copy_from_slice(buffer);
+        Ok(())
+    }
+
+    fn write_header(&mut self, page_id: u32, generation: u32) {
+        put_u32(&mut self.buffer, 0, page_id);
+        put_u32(&mut self.buffer, 4, PageType::FreeList as u32);
+        put_u32(&mut self.buffer, 8, self.next_page_id);
+        put_u16(&mut self.buffer, 12, self.len);
+        put_sentinel(&mut self.buffer, FREE_LIST_GENERATION_OFFSET, generation);
+    }
+}
+
+// the entry count is a u16, so pages beyond 256 KiB leave their tail unused
+fn free_list_capacity(page_size: usize) -> usize {
+    ((page_size - PAGE_TRAILER_SIZE - FREE_LIST_HEADER_SIZE) / 4).min(u16::MAX as usize)
+}
+
+fn entry_offset(idx: u16) -> usize {
+    FREE_LIST_HEADER_SIZE + idx as usize * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::freelist::{free_list_capacity, FreeListPage};
+    use crate::io::store::PageStore;
+    use crate::io::PAGE_SIZE;
+    use tempfile::tempfile;
+
+    const TESTDB_MAX_SIZE: usize = 8 * 1024 * 1024;
+
+    #[test]
+    fn pops_in_reverse_push_order() {
+        let mut store = temporary_store();
+        let mut free_list = FreeListPage::new(1);
+
+        for page_id in 10..15 {
+            free_list.push(page_id, &mut store).unwrap();
+        }
+
+        for page_id in (10..15).rev() {
+            assert_eq!(Some(page_id), free_list.pop(&store).unwrap());
+        }
+        assert_eq!(None, free_list.pop(&store).unwrap());
+        assert!(free_list.is_empty());
+    }
+
+    #[test]
+    fn full_page_spills_into_freed_page() {
+        let mut store = temporary_store();
+        let mut free_list = FreeListPage::new(1);
+        let spill_page_id = 10 + free_list_capacity(PAGE_SIZE) as u32;
+
+        for page_id in 10..spill_page_id + 2 {
+            free_list.push(page_id, &mut store).unwrap();
+        }
+        assert_eq!(Some(spill_page_id), free_list.next_page_id());
+        assert_eq!(1, free_list.len());
+
+        assert_eq!(Some(spill_page_id + 1), free_list.pop(&store).unwrap());
+        assert_eq!(Some(spill_page_id), free_list.pop(&store).unwrap());
+        assert_eq!(None, free_list.next_page_id());
+        for page_id in (10..spill_page_id).rev() {
+            assert_eq!(Some(page_id), free_list.pop(&store).unwrap());
+        }
+        assert_eq!(None, free_list.pop(&store).unwrap());
+    }
+
+    #[test]
+    fn persists_and_loads_chain() {
+        let mut store = temporary_store();
+        let mut free_list = FreeListPage::new(1);
+        let spill_page_id = 10 + free_list_capacity(PAGE_SIZE) as u32;
+        for page_id in 10..spill_page_id + 2 {
+            free_list.push(page_id, &mut store).unwrap();
+        }
+        free_list.persist(&mut store).unwrap();
+
+        let mut loaded = FreeListPage::load(&store.read_page(1).unwrap()).unwrap();
+
+        assert_eq!(1, loaded.page_id());
+        assert_eq!(Some(spill_page_id), loaded.next_page_id());
+        let popped = std::iter::from_fn(|| loaded.pop(&store).unwrap()).collect::<Vec<_>>();
+        assert_eq!(free_list_capacity(PAGE_SIZE) + 2, popped.len());
+        assert_eq!(Some(&10), popped.last());
+    }
+
+    #[test]
+    fn persists_and_loads_chain_of_store_page_size() {
+        let page_size = 1024;
+        let file = tempfile().unwrap();
+        let mut store = PageStore::with_page_size(file, 512 * page_size, page_size).unwrap();
+        let mut free_list = FreeListPage::with_page_size(1, page_size).unwrap();
+        let spill_page_id = 10 + free_list.capacity() as u32;
+        for page_id in 10..spill_page_id + 2 {
+            free_list.push(page_id, &mut store).unwrap();
+        }
+        free_list.persist(&mut store).unwrap();
+
+        let mut loaded = FreeListPage::load(&store.read_page(1).unwrap()).unwrap();
+
+        assert_eq!(free_list_capacity(page_size), loaded.capacity());
+        assert_eq!(Some(spill_page_id), loaded.next_page_id());
+        let popped = std::iter::from_fn(|| loaded.pop(&store).unwrap()).collect::<Vec<_>>();
+        assert_eq!(free_list.capacity() + 2, popped.len());
+    }
+
+    fn temporary_store() -> PageStore {
+        let file = tempfile().unwrap();
+        PageStore::new(file, TESTDB_MAX_SIZE).unwrap()
+    }
+}
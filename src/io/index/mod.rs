@@ -1,162 +1,955 @@
-use crate::io::{PAGE_SIZE, PageType};
-use crate::io::bitmap::{BitmapPage, BITMAP_PAGE_COUNT, BitmapHeader};
+use crate::io::{MIN_PAGE_SIZE, PAGE_SIZE, PAGE_TRAILER_SIZE, PageIdWidth, PageType, check_page_size, expect_sized_page, get_page_id, header_page_id, header_prefix_size, invalid_data, invalid_input, put_header_prefix, put_page_id, put_sentinel, check_sentinel, put_u16, put_u32};
+use crate::io::bitmap::{AllocOutcome, BitmapPage, bitmap_page_count, max_first_managed_page_id, BitmapHeader, FreeHintStatus};
 use crate::io::store::{MemoryPage, PageStore};
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::io::Result;
+use std::io::{Error, Result};
+use std::ops::Range;
 use std::pin::Pin;
 
 #[cfg(test)]
 mod tests;
 
-const INDEX_HEADER_SIZE: usize = 16;
-const INDEX_BITMAP_COUNT: u16 = ((PAGE_SIZE - INDEX_HEADER_SIZE) / 8) as u16;
-const INDEX_FREE_PAGE_OFFSET: usize = INDEX_BITMAP_COUNT as usize * 4;
+const INDEX_FORMAT_VERSION: u16 = 3;
+const NO_PARENT: u64 = u64::MAX;
+const NO_NEXT: u64 = u64::MAX;
+const PREFETCH_THRESHOLD: u16 = 64;
+// bitmaps an index on a default sized page can hold
+pub const INDEX_BITMAP_COUNT: u16 = index_bitmap_count(PAGE_SIZE, PageIdWidth::U32);
+
+// page id 0..4, type 4..8, first managed page 8..12, bitmap count 12..14, first free bitmap 14..16,
+// generation 16..20, parent page id 20..24, format version 24..26, 2 bytes spare, next index page id 28..32.
+// with u64 page ids the header prefix (see header_prefix_size), the parent and the next index are wider
+const fn index_header_size(width: PageIdWidth) -> usize {
+    next_offset(width) + width.bytes()
+}
+
+const fn generation_offset(width: PageIdWidth) -> usize {
+    header_prefix_size(width) + 4
+}
+
+const fn parent_offset(width: PageIdWidth) -> usize {
+    header_prefix_size(width) + 8
+}
+
+const fn version_offset(width: PageIdWidth) -> usize {
+    parent_offset(width) + width.bytes()
+}
+
+const fn next_offset(width: PageIdWidth) -> usize {
+    version_offset(width) + 4
+}
+
+const _: () = assert!(generation_offset(PageIdWidth::U64) + 4 <= parent_offset(PageIdWidth::U64));
+const _: () = assert!(next_offset(PageIdWidth::U64) + PageIdWidth::U64.bytes() <= index_header_size(PageIdWidth::U64));
+
+// each bitmap takes a page id and a free page count
+pub const fn index_bitmap_count(page_size: usize, width: PageIdWidth) -> u16 {
+    let slots = page_size.saturating_sub(index_header_size(width) + PAGE_TRAILER_SIZE) / (width.bytes() + 4);
+    if slots > u16::MAX as usize {
+        u16::MAX
+    } else {
+        slots as u16
+    }
+}
+
+// grow needs room for the first two bitmaps on the smallest page
+const _: () = assert!(index_bitmap_count(MIN_PAGE_SIZE, PageIdWidth::U64) >= 2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexSlot {
+    pub bitmap_idx: u16,
+    pub bitmap_page_id: u64,
+    pub free_page_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    pub current_high_water: u64,
+    pub packed_high_water: u64,
+    pub pages_reclaimable: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PersistCost {
+    pub dirty_bitmap_writes: usize,
+    pub index_writes: usize,
+    pub contiguous_runs: usize,
+    pub estimated_syscalls: usize,
+}
+
+// first managed page 0..8, index page id 8..16, bitmap count 16..18, page size 18..22, page id width 22..24
+const STATE_HEADER_SIZE: usize = 24;
+
+pub struct AllocatorStateBlob {
+    bytes: Vec<u8>,
+}
+
+impl AllocatorStateBlob {
+    // one segment per index of the chain, each a header followed by its bitmaps
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<AllocatorStateBlob> {
+        let mut offset = 0;
+        loop {
+            if bytes.len() < offset + STATE_HEADER_SIZE {
+                return invalid_data(format!("invalid allocator state, unexpected length {}", bytes.len()));
+            }
+            let page_size = get_u32(&bytes, offset + 18) as usize;
+            if check_page_size(page_size).is_err() {
+                return invalid_data(format!("invalid allocator state, unsupported page size {}", page_size));
+            }
+            let width = state_width(&bytes[offset..])?;
+            offset += segment_size(get_u16(&bytes, offset + 16), page_size, width);
+            if offset == bytes.len() {
+                return Ok(AllocatorStateBlob { bytes });
+            }
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
 
 pub struct IndexPage {
-    page_id: u32,
-    first_managed_page_id: u32,
+    page_id: u64,
+    first_managed_page_id: u64,
+    width: PageIdWidth,
     current_bitmap_count: u16,
     current_bitmap_idx: u16,
     first_free_bitmap_idx: u16,
+    generation: u32,
+    parent_page_id: u64,
+    next_index_page_id: u64,
+    next: Option<Pin<Box<IndexPage>>>,
+    // kept in memory only, the reserved pages themselves are persisted as used
+    reserved: Vec<Range<u64>>,
     dirty_bitmaps: HashMap<u16, Pin<Box<BitmapPage>>>,
-    buffer: [u8; PAGE_SIZE],
+    buffer: Vec<u8>,
 }
 
-impl<'a> IndexPage {
-    pub fn grow(bitmap: Pin<Box<BitmapPage>>) -> Pin<Box<IndexPage>> {
-        let mut second = BitmapPage::new(bitmap.first_managed_page_id() + BITMAP_PAGE_COUNT as u32);
+impl IndexPage {
+    // the index takes the page size and page id width of the bitmap it grows from
+    pub fn grow(bitmap: Pin<Box<BitmapPage>>) -> Result<Pin<Box<IndexPage>>> {
+        let page_size = bitmap.page_size();
+        let width = bitmap.id_width();
+        let second_first_managed_page_id = match bitmap.first_managed_page_id().checked_add(bitmap.managed_page_count() as u64) {
+            Some(second_first_managed_page_id) => second_first_managed_page_id,
+            None => return invalid_input(
+                format!("invalid bitmap, no room for a second bitmap after pages from {}", bitmap.first_managed_page_id())
+            ),
+        };
+        let mut second = BitmapPage::with_layout(second_first_managed_page_id, second_first_managed_page_id, page_size, width)?;
 
         let page_id = second.allocate(|_| true).unwrap();
 
         let mut index = Box::pin(IndexPage {
             page_id,
             first_managed_page_id: bitmap.first_managed_page_id(),
+            width,
             current_bitmap_count: 2,
             current_bitmap_idx: 1,
             first_free_bitmap_idx: if bitmap.free_page_count() > 0 { 0 } else { 1 },
+            generation: 0,
+            parent_page_id: NO_PARENT,
+            next_index_page_id: NO_NEXT,
+            next: None,
+            reserved: Vec::new(),
             dirty_bitmaps: HashMap::new(),
-            buffer: [0; PAGE_SIZE],
+            buffer: vec![0; page_size],
         });
         index.update(&bitmap);
         index.update(&second);
         index.dirty_bitmaps.insert(0, bitmap);
         index.dirty_bitmaps.insert(1, second);
-        index
+        Ok(index)
     }
 
-    pub fn load(memory: &MemoryPage, page_store: &PageStore, mut f: impl FnMut(u32) -> bool) -> Option<Pin<Box<IndexPage>>> {
-        let old_page_id = memory.page_id();
-        let first_managed_page_id = memory.get_u32(8);
-        let current_bitmap_count = memory.get_u16(12);
-        let first_free_bitmap_idx = memory.get_u16(14);
+    pub fn load(memory: &MemoryPage, page_store: &PageStore, mut f: impl FnMut(u64) -> bool) -> Result<Option<Pin<Box<IndexPage>>>> {
+        let mut index = IndexPage::view(memory)?;
+        let old_page_id = index.page_id;
+        index.page_id = u64::MAX;
 
-        let mut buffer = [0; PAGE_SIZE];
-        buffer.copy_from_slice(memory.content());
+        if let Some(page_id) = index.allocate(page_store, &mut f)? {
+            index.page_id = page_id;
+            if index.free(old_page_id, page_store, &mut f)?.is_some() {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
 
-        let mut index = Box::pin(IndexPage {
-            page_id: 0xFFFF_FFFF,
+    // the index as stored, without relocating it, for reading a chained index that is not loaded
+    pub(crate) fn view(memory: &MemoryPage) -> Result<Pin<Box<IndexPage>>> {
+        let buffer = expect_sized_page(memory.content())?.to_vec();
+        let width = PageIdWidth::of(&buffer);
+        let prefix = header_prefix_size(width);
+        let generation = check_sentinel(&buffer, generation_offset(width))?;
+        let first_managed_page_id = get_page_id(&buffer, 8, width);
+        let current_bitmap_count = memory.get_u16(prefix);
+        let first_free_bitmap_idx = memory.get_u16(prefix + 2);
+        let parent_page_id = get_page_id(&buffer, parent_offset(width), width);
+        let next_index_page_id = get_page_id(&buffer, next_offset(width), width);
+        let version = memory.get_u16(version_offset(width));
+        if version != INDEX_FORMAT_VERSION {
+            return invalid_data(
+                format!("unsupported index format version {} (expected {})", version, INDEX_FORMAT_VERSION)
+            );
+        }
+
+        Ok(Box::pin(IndexPage {
+            page_id: header_page_id(&buffer),
             first_managed_page_id,
+            width,
             current_bitmap_count,
             current_bitmap_idx: first_free_bitmap_idx,
             first_free_bitmap_idx,
+            generation,
+            parent_page_id,
+            next_index_page_id,
+            next: None,
+            reserved: Vec::new(),
             dirty_bitmaps: HashMap::new(),
             buffer,
-        });
+        }))
+    }
 
-        if index.activate_next_bitmap(page_store, first_free_bitmap_idx, &mut f) {
-            index.page_id = index.allocate(page_store, &mut f)?;
-            index.free(old_page_id, page_store, &mut f)?;
-            Some(index)
-        } else {
-            None
+    // visits this index and every index chained after it, reading the ones that are not loaded
+    fn visit_chain(&self, page_store: &PageStore, f: &mut dyn FnMut(&IndexPage) -> Result<()>) -> Result<()> {
+        f(self)?;
+        match &self.next {
+            Some(next) => next.visit_chain(page_store, f),
+            None if self.next_index_page_id != NO_NEXT => {
+                let memory = page_store.read_page(self.next_index_page_id as usize)?;
+                IndexPage::view(&memory)?.visit_chain(page_store, f)
+            }
+            None => Ok(()),
         }
     }
 
     pub fn persist(&mut self, page_store: &mut PageStore) -> Result<()> {
-        self.dirty_bitmaps.iter_mut().map(|(_, v)| {
-            v.persist(page_store)
-        }).filter(|r| r.is_err()).collect::<Result<Vec<_>>>()?;
+        if let Some(next) = self.next.as_mut() {
+            next.parent_page_id = self.page_id;
+            next.persist(page_store)?;
+            self.next_index_page_id = next.page_id;
+        }
+        let mut dirty = self.dirty_bitmaps.iter_mut().filter(|(_, bitmap)| bitmap.is_dirty()).collect::<Vec<_>>();
+        dirty.sort_unstable_by_key(|(idx, _)| **idx);
+        for (idx, bitmap) in dirty {
+            if let Err(e) = bitmap.persist(page_store) {
+                return Err(Error::new(e.kind(), format!("bitmap {} (page {}) could not be persisted: {}", idx, bitmap.page_id, e)));
+            }
+        }
 
+        self.generation = self.generation.wrapping_add(1);
         self.update_header();
-        page_store.write_page(self.page_id as usize, &self.buffer)
+        Ok(page_store.write_page(self.page_id as usize, &self.buffer)?)
+    }
+
+    // the pages the next persist writes to, this index, its dirty bitmaps and the loaded chained indexes
+    pub(crate) fn pending_writes(&self) -> Vec<u64> {
+        let mut pages = self.dirty_bitmaps.values()
+            .filter(|bitmap| bitmap.is_dirty())
+            .map(|bitmap| bitmap.page_id)
+            .collect::<Vec<_>>();
+        pages.push(self.page_id);
+        if let Some(next) = &self.next {
+            pages.extend(next.pending_writes());
+        }
+        pages
+    }
+
+    // page writes are copies into the mapping, the cost comes with the flush. it writes back each contiguous
+    // run of pages as one request and finishes with a single fdatasync
+    pub fn persist_cost(&self) -> PersistCost {
+        let mut page_ids = self.dirty_bitmaps.values()
+            .filter(|bitmap| bitmap.is_dirty())
+            .map(|bitmap| bitmap.page_id)
+            .collect::<Vec<_>>();
+        let dirty_bitmap_writes = page_ids.len();
+        page_ids.push(self.page_id);
+        page_ids.sort_unstable();
+
+        let contiguous_runs = 1 + page_ids.windows(2).filter(|w| w[1] != w[0] + 1).count();
+        PersistCost {
+            dirty_bitmap_writes,
+            index_writes: 1,
+            contiguous_runs,
+            estimated_syscalls: contiguous_runs + 1,
+        }
+    }
+
+    pub fn persist_and_reload(mut self: Pin<Box<Self>>, page_store: &mut PageStore, f: impl FnMut(u64) -> bool) -> Result<Option<Pin<Box<IndexPage>>>> {
+        self.persist(page_store)?;
+
+        let memory = page_store.read_page(self.page_id as usize)?;
+        IndexPage::load(&memory, page_store, f)
     }
 
     fn update_header(&mut self) {
-        put_u32(&mut self.buffer, 0, self.page_id);
-        put_u32(&mut self.buffer, 4, PageType::Index as u32);
-        put_u32(&mut self.buffer, 8, self.first_managed_page_id);
-        put_u16(&mut self.buffer, 12, self.current_bitmap_count);
-        put_u16(&mut self.buffer, 14, self.first_free_bitmap_idx);
+        let (width, prefix) = (self.width, header_prefix_size(self.width));
+        put_header_prefix(&mut self.buffer, width.index_type(), width, self.page_id, self.first_managed_page_id);
+        put_u16(&mut self.buffer, prefix, self.current_bitmap_count);
+        put_u16(&mut self.buffer, prefix + 2, self.first_free_bitmap_idx);
+        put_sentinel(&mut self.buffer, generation_offset(width), self.generation);
+        put_page_id(&mut self.buffer, parent_offset(width), width, self.parent_page_id);
+        put_u16(&mut self.buffer, version_offset(width), INDEX_FORMAT_VERSION);
+        put_page_id(&mut self.buffer, next_offset(width), width, self.next_index_page_id);
     }
 
-    fn activate_next_bitmap(&mut self, page_store: &PageStore, bitmap_idx: u16, mut f: &mut impl FnMut(u32) -> bool) -> bool {
-        let content = &self.buffer[INDEX_HEADER_SIZE..];
+    fn activate_next_bitmap(&mut self, page_store: &PageStore, bitmap_idx: u16, mut f: &mut impl FnMut(u64) -> bool) -> Result<bool> {
         for idx in bitmap_idx..self.current_bitmap_count {
-            let free_page_count = get_u32(content, INDEX_FREE_PAGE_OFFSET + idx as usize * 4);
-            if free_page_count == 0 {
+            if self.slot_free_page_count(idx) == 0 {
                 continue;
             }
 
-            let bitmap_page_id = get_u32(content, idx as usize * 4);
-            let bitmap_page = page_store.read_page(bitmap_page_id as usize).unwrap();
+            if self.dirty_bitmaps.contains_key(&idx) {
+                self.current_bitmap_idx = idx;
+                return Ok(true);
+            }
+
+            let bitmap_page_id = self.slot_page_id(idx);
+            let bitmap_page = page_store.read_page(bitmap_page_id as usize)?;
 
-            if let Some(bitmap) = BitmapPage::load(&bitmap_page, &mut f) {
+            if let Some(bitmap) = BitmapPage::load(&bitmap_page, &mut f)? {
                 let freed = bitmap.contains(bitmap_page_id);
                 self.update(&bitmap);
                 self.current_bitmap_idx = idx;
                 self.dirty_bitmaps.insert(idx, bitmap);
-                if !freed {
-                    match self.free(bitmap_page_id, page_store, f) {
-                        None => return false,
-                        Some(_) => ()
-                    }
+                if !freed && self.free(bitmap_page_id, page_store, f)?.is_none() {
+                    return Ok(false);
                 }
-                return true;
+                return Ok(true);
             }
         }
 
-        self.grow_next_bitmap()
+        Ok(self.grow_next_bitmap())
+    }
+
+    fn prefetch_next_bitmap(&self, page_store: &PageStore) {
+        let next = (self.current_bitmap_idx + 1..self.current_bitmap_count)
+            .find(|idx| self.slot_free_page_count(*idx) > 0);
+        if let Some(idx) = next {
+            if !self.dirty_bitmaps.contains_key(&idx) {
+                page_store.prefetch(self.slot_page_id(idx) as usize, 1);
+            }
+        }
     }
 
     fn grow_next_bitmap(&mut self) -> bool {
-        let result = self.current_bitmap_count < INDEX_BITMAP_COUNT;
-        if result {
-            let bitmap = BitmapPage::new(self.first_managed_page_id + self.current_bitmap_count as u32 * BITMAP_PAGE_COUNT as u32);
-            self.update(&bitmap);
-            self.dirty_bitmaps.insert(self.current_bitmap_count, bitmap);
-            self.current_bitmap_idx = self.current_bitmap_count;
-            self.current_bitmap_count += 1;
+        if self.is_at_capacity() {
+            return false;
         }
-        result
+        let first_managed_page_id = (self.current_bitmap_count as u64)
+            .checked_mul(self.pages_per_bitmap())
+            .and_then(|offset| self.first_managed_page_id.checked_add(offset))
+            .filter(|first| *first <= max_first_managed_page_id(self.page_size(), self.width));
+        let first_managed_page_id = match first_managed_page_id {
+            Some(first_managed_page_id) => first_managed_page_id,
+            None => return false,
+        };
+
+        let bitmap = match BitmapPage::with_layout(first_managed_page_id, first_managed_page_id, self.page_size(), self.width) {
+            Ok(bitmap) => bitmap,
+            Err(_) => return false,
+        };
+        self.update(&bitmap);
+        self.dirty_bitmaps.insert(self.current_bitmap_count, bitmap);
+        self.current_bitmap_idx = self.current_bitmap_count;
+        self.current_bitmap_count += 1;
+        true
     }
 
-    pub fn allocate(&mut self, page_store: &PageStore, mut f: &mut impl FnMut(u32) -> bool) -> Option<u32> {
+    pub fn allocate(&mut self, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<Option<u64>> {
+        let mut unbounded = u32::MAX;
+        match self.allocate_within(page_store, &mut unbounded, f)? {
+            AllocOutcome::Allocated(page_id) => Ok(Some(page_id)),
+            _ => Ok(None),
+        }
+    }
+
+    // the budget counts bitmap bytes scanned and is shared by every bitmap and chained index the scan visits
+    pub fn allocate_with_budget(&mut self, page_store: &PageStore, budget: u32, f: &mut impl FnMut(u64) -> bool) -> Result<AllocOutcome> {
+        let mut budget = budget;
+        self.allocate_within(page_store, &mut budget, f)
+    }
+
+    // marks the pages used and keeps them from being allocated again, even after they are freed.
+    // the pages have to lie within the bitmaps of this index
+    pub fn reserve(&mut self, pages: Range<u64>, page_store: &PageStore) -> Result<bool> {
+        if pages.is_empty() {
+            return Ok(true);
+        }
+        let bitmaps_end = self.first_managed_page_id + self.total_managed_pages();
+        if pages.start < self.first_managed_page_id || pages.end > bitmaps_end {
+            return Ok(false);
+        }
+        self.reserved.push(pages.clone());
+
+        let first_idx = ((pages.start - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
+        let last_idx = ((pages.end - 1 - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
+        for idx in first_idx..=last_idx {
+            if !self.load_bitmap_for_update(idx, page_store, &mut |_| true)? {
+                return Ok(false);
+            }
+            let bitmap = match self.dirty_bitmaps.get_mut(&idx) {
+                Some(bitmap) => bitmap,
+                None => return Ok(false),
+            };
+            let bitmap_pages = bitmap.first_managed_page_id..bitmap.first_managed_page_id + bitmap.managed_page_count() as u64;
+            bitmap.reserve(pages.start.max(bitmap_pages.start)..pages.end.min(bitmap_pages.end));
+            let (page_id, free_page_count) = (bitmap.page_id, bitmap.free_page_count);
+            self.update_bitmap_data(idx, page_id, free_page_count);
+        }
+        Ok(true)
+    }
+
+    pub fn is_allocated(&self, page_id: u64, page_store: &PageStore) -> Result<Option<bool>> {
+        if page_id < self.first_managed_page_id || page_id - self.first_managed_page_id >= self.total_managed_pages() {
+            return Ok(None);
+        }
+        let idx = ((page_id - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
+        match self.dirty_bitmaps.get(&idx) {
+            Some(bitmap) => Ok(bitmap.is_allocated(page_id)),
+            None => {
+                let bitmap_page_id = self.slot_page_id(idx);
+                let bitmap_memory = page_store.read_page(bitmap_page_id as usize)?;
+                Ok(BitmapPage::load_into(&bitmap_memory, bitmap_page_id)?.is_allocated(page_id))
+            }
+        }
+    }
+
+    fn allocate_within(&mut self, page_store: &PageStore, budget: &mut u32, f: &mut impl FnMut(u64) -> bool) -> Result<AllocOutcome> {
         loop {
-            let bitmap = self.dirty_bitmaps.get_mut(&self.current_bitmap_idx).unwrap();
-            let result = bitmap.allocate(&mut f);
+            let bitmap = match self.dirty_bitmaps.get_mut(&self.current_bitmap_idx) {
+                Some(bitmap) if self.current_bitmap_idx < self.current_bitmap_count => bitmap,
+                _ => {
+                    if !self.activate_next_bitmap(page_store, self.first_free_bitmap_idx, f)? {
+                        return self.allocate_in_next(page_store, budget, f);
+                    }
+                    continue;
+                }
+            };
+            let reserved = &self.reserved;
+            let outcome = bitmap.allocate_within(budget, |page_id| !is_reserved(reserved, page_id) && f(page_id));
             let page_id = bitmap.page_id;
             let free_page_count = bitmap.free_page_count;
 
             self.update_bitmap_data(self.current_bitmap_idx, page_id, free_page_count);
-            if result.is_some() {
-                return result;
-            } else if !self.activate_next_bitmap(page_store, self.current_bitmap_idx + 1, f) {
-                return None;
+            if free_page_count == PREFETCH_THRESHOLD {
+                self.prefetch_next_bitmap(page_store);
+            }
+            if outcome != AllocOutcome::Full {
+                return Ok(outcome);
+            } else if !self.activate_next_bitmap(page_store, self.current_bitmap_idx + 1, f)? {
+                return self.allocate_in_next(page_store, budget, f);
+            }
+        }
+    }
+
+    fn allocate_in_next(&mut self, page_store: &PageStore, budget: &mut u32, f: &mut impl FnMut(u64) -> bool) -> Result<AllocOutcome> {
+        match self.next_index(page_store, f, true)? {
+            Some(next) => next.allocate_within(page_store, budget, f),
+            None => Ok(AllocOutcome::Full),
+        }
+    }
+
+    // a saturated index continues in a chained index managing the pages right after its last bitmap.
+    // the chain is loaded on first use, and only allocation from a full index may start a new one,
+    // so a filter rejecting every free page does not keep chaining empty indexes
+    fn next_index(&mut self, page_store: &PageStore, f: &mut dyn FnMut(u64) -> bool, create: bool) -> Result<Option<&mut IndexPage>> {
+        if self.next.is_none() {
+            let next = if self.next_index_page_id != NO_NEXT {
+                let memory = page_store.read_page(self.next_index_page_id as usize)?;
+                IndexPage::load(&memory, page_store, &mut *f)?
+            } else if create && self.is_at_capacity() && self.total_free_pages() == 0 {
+                match self.chained_first_managed_page_id() {
+                    Some(first) => Some(IndexPage::grow(BitmapPage::with_layout(first, first, self.page_size(), self.width)?)?),
+                    None => None,
+                }
+            } else {
+                None
+            };
+            match next {
+                Some(mut next) => {
+                    next.parent_page_id = self.page_id;
+                    self.next_index_page_id = next.page_id;
+                    self.next = Some(next);
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(self.next.as_deref_mut())
+    }
+
+    fn chained_first_managed_page_id(&self) -> Option<u64> {
+        let max_first_managed_page_id = max_first_managed_page_id(self.page_size(), self.width);
+        self.managed_end()
+            .filter(|first| first.checked_add(self.pages_per_bitmap()).is_some_and(|second| second <= max_first_managed_page_id))
+    }
+
+    fn managed_end(&self) -> Option<u64> {
+        (self.capacity() as u64).checked_mul(self.pages_per_bitmap())
+            .and_then(|pages| pages.checked_add(self.first_managed_page_id))
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn id_width(&self) -> PageIdWidth {
+        self.width
+    }
+
+    fn pages_per_bitmap(&self) -> u64 {
+        bitmap_page_count(self.buffer.len(), self.width) as u64
+    }
+
+    fn capacity(&self) -> u16 {
+        index_bitmap_count(self.buffer.len(), self.width)
+    }
+
+    // the free page counts follow the page ids of all the bitmaps the index can hold
+    fn free_page_offset(&self) -> usize {
+        self.capacity() as usize * self.width.bytes()
+    }
+
+    fn slot_page_id(&self, bitmap_idx: u16) -> u64 {
+        get_page_id(&self.buffer, index_header_size(self.width) + bitmap_idx as usize * self.width.bytes(), self.width)
+    }
+
+    fn slot_free_page_count(&self, bitmap_idx: u16) -> u32 {
+        get_u32(&self.buffer, index_header_size(self.width) + self.free_page_offset() + bitmap_idx as usize * 4)
+    }
+
+    pub fn allocate_in_emptiest(&mut self, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<Option<u64>> {
+        let emptiest = self.slots()
+            .filter(|slot| slot.free_page_count > 0)
+            .max_by_key(|slot| (slot.free_page_count, Reverse(slot.bitmap_idx)));
+        if let Some(slot) = emptiest {
+            if !self.activate_next_bitmap(page_store, slot.bitmap_idx, f)? {
+                return Ok(None);
             }
         }
+        self.allocate(page_store, f)
+    }
+
+    pub fn refresh_cursor(&mut self, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<bool> {
+        let first_free_bitmap_idx = self.slots()
+            .find(|slot| slot.free_page_count > 0)
+            .map_or(self.current_bitmap_count, |slot| slot.bitmap_idx);
+        self.first_free_bitmap_idx = first_free_bitmap_idx;
+        for bitmap in self.dirty_bitmaps.values_mut() {
+            bitmap.reset_cursor();
+        }
+        self.activate_next_bitmap(page_store, self.first_free_bitmap_idx, f)
     }
 
-    pub fn free(&mut self, page_id: u32, page_store: &PageStore, f: &mut impl FnMut(u32) -> bool) -> Option<bool> {
+    pub fn free(&mut self, page_id: u64, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<Option<bool>> {
+        if page_id < self.first_managed_page_id {
+            return Ok(None);
+        }
+        if self.managed_end().is_some_and(|end| page_id >= end) {
+            return match self.next_index(page_store, f, false)? {
+                Some(next) => next.free(page_id, page_store, f),
+                None => Ok(None),
+            };
+        }
         let freed = self.free_dirty(page_id);
         if freed.is_some() {
-            return freed;
+            return Ok(freed);
         }
 
         self.free_unloaded(page_id, page_store, f)
     }
 
-    fn free_dirty(&mut self, page_id: u32) -> Option<bool> {
-        let idx = ((page_id - self.first_managed_page_id) / BITMAP_PAGE_COUNT as u32) as u16;
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+
+    pub fn parent_page_id(&self) -> Option<u64> {
+        if self.parent_page_id == NO_PARENT {
+            None
+        } else {
+            Some(self.parent_page_id)
+        }
+    }
+
+    pub fn set_parent_page_id(&mut self, parent_page_id: Option<u64>) {
+        self.parent_page_id = parent_page_id.unwrap_or(NO_PARENT);
+    }
+
+    pub fn next_index_page_id(&self) -> Option<u64> {
+        if self.next_index_page_id == NO_NEXT {
+            None
+        } else {
+            Some(self.next_index_page_id)
+        }
+    }
+
+    pub fn bitmaps_lazy<'a>(&'a self, page_store: &'a PageStore) -> impl Iterator<Item = Result<Pin<Box<BitmapPage>>>> + 'a {
+        (0..self.current_bitmap_count).map(move |idx| {
+            let bitmap_page_id = self.slot_page_id(idx);
+            let bitmap_memory = page_store.read_page(bitmap_page_id as usize)?;
+            BitmapPage::load_into(&bitmap_memory, bitmap_page_id)
+        })
+    }
+
+    pub fn validate_coverage(&self, page_store: &PageStore) -> Result<()> {
+        for idx in 0..self.current_bitmap_count {
+            let bitmap_page_id = self.slot_page_id(idx);
+            let bitmap_header = page_store.read_header(bitmap_page_id as usize)?;
+
+            let expected = self.first_managed_page_id + idx as u64 * self.pages_per_bitmap();
+            let actual = bitmap_header.first_managed_page_id();
+            if actual != expected {
+                return invalid_data(
+                    format!("bitmap {} (page {}) manages pages from {} but should start at {}", idx, bitmap_page_id, actual, expected)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn compaction_savings(&self, page_store: &PageStore) -> Result<CompactionEstimate> {
+        let mut allocated = 0;
+        let mut current_high_water = self.first_managed_page_id;
+        self.visit_bitmaps(page_store, |_, bitmap| {
+            allocated += (bitmap.managed_page_count() - bitmap.free_page_count) as u64;
+            if let Some(page_id) = bitmap.last_allocated_page() {
+                current_high_water = current_high_water.max(page_id + 1);
+            }
+        })?;
+
+        let packed_high_water = self.first_managed_page_id + allocated;
+        Ok(CompactionEstimate {
+            current_high_water,
+            packed_high_water,
+            pages_reclaimable: current_high_water.saturating_sub(packed_high_water),
+        })
+    }
+
+    pub fn audit_free_hints(&self, page_store: &PageStore) -> Result<Vec<(u16, FreeHintStatus)>> {
+        let mut statuses = Vec::with_capacity(self.current_bitmap_count as usize);
+        self.visit_bitmaps(page_store, |idx, bitmap| {
+            statuses.push((idx, bitmap.validate_free_hint()));
+        })?;
+        Ok(statuses)
+    }
+
+    // bitmaps whose bits disagree with their own free count or with the free count recorded in their slot,
+    // as (index page, bitmap slot) across the whole chain
+    pub fn verify(&self, page_store: &PageStore) -> Result<Vec<(u64, u16)>> {
+        let mut mismatched = Vec::new();
+        self.visit_chain(page_store, &mut |index| {
+            let recorded = index.slots().map(|slot| slot.free_page_count).collect::<Vec<_>>();
+            index.visit_bitmaps(page_store, |idx, bitmap| {
+                if !bitmap.verify() || recorded[idx as usize] != bitmap.free_page_count as u32 {
+                    mismatched.push((index.page_id, idx));
+                }
+            })
+        })?;
+        Ok(mismatched)
+    }
+
+    // free runs are tracked per bitmap, so a run never spans two bitmaps
+    pub fn largest_free_run(&self, page_store: &PageStore) -> Result<u32> {
+        let mut largest = 0;
+        self.visit_chain(page_store, &mut |index| {
+            index.visit_bitmaps(page_store, |_, bitmap| {
+                largest = largest.max(bitmap.largest_free_run() as u32);
+            })
+        })?;
+        Ok(largest)
+    }
+
+    pub fn find_duplicate_bitmap_slots(&self) -> Vec<(u16, u16)> {
+        let mut first_slots = HashMap::new();
+        let mut duplicates = Vec::new();
+        for slot in self.slots().filter(|slot| slot.bitmap_page_id != 0) {
+            match first_slots.get(&slot.bitmap_page_id) {
+                Some(&first_idx) => duplicates.push((first_idx, slot.bitmap_idx)),
+                None => {
+                    first_slots.insert(slot.bitmap_page_id, slot.bitmap_idx);
+                }
+            }
+        }
+        duplicates
+    }
+
+    pub fn slots(&self) -> impl Iterator<Item = IndexSlot> + '_ {
+        (0..self.current_bitmap_count).map(move |idx| IndexSlot {
+            bitmap_idx: idx,
+            bitmap_page_id: self.slot_page_id(idx),
+            free_page_count: self.slot_free_page_count(idx),
+        })
+    }
+
+    fn visit_bitmaps(&self, page_store: &PageStore, mut f: impl FnMut(u16, &BitmapPage)) -> Result<()> {
+        for idx in 0..self.current_bitmap_count {
+            match self.dirty_bitmaps.get(&idx) {
+                Some(bitmap) => f(idx, bitmap),
+                None => {
+                    let bitmap_page_id = self.slot_page_id(idx);
+                    let bitmap_memory = page_store.read_page(bitmap_page_id as usize)?;
+                    let bitmap = BitmapPage::load_into(&bitmap_memory, bitmap_page_id)?;
+                    f(idx, &bitmap);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn first_free_page(&self, page_store: &PageStore) -> Option<u64> {
+        let idx = self.first_free_bitmap_idx;
+        if idx >= self.current_bitmap_count {
+            return None;
+        }
+
+        let (first_managed_page_id, first_free_page_idx) = match self.dirty_bitmaps.get(&idx) {
+            Some(bitmap) => (bitmap.first_managed_page_id(), bitmap.first_free_page_index()),
+            None => {
+                let bitmap_page_id = self.slot_page_id(idx);
+                let header = page_store.read_header(bitmap_page_id as usize).ok()?;
+                (header.first_managed_page_id(), header.first_free_page_index())
+            }
+        };
+        if first_free_page_idx as u64 >= self.pages_per_bitmap() {
+            return None;
+        }
+        Some(first_managed_page_id + first_free_page_idx as u64)
+    }
+
+    pub fn export_state(&self, page_store: &PageStore) -> Result<AllocatorStateBlob> {
+        let mut bytes = Vec::with_capacity(segment_size(self.current_bitmap_count, self.page_size(), self.width));
+        self.visit_chain(page_store, &mut |index| {
+            bytes.extend_from_slice(&index.first_managed_page_id.to_le_bytes());
+            bytes.extend_from_slice(&index.page_id.to_le_bytes());
+            bytes.extend_from_slice(&index.current_bitmap_count.to_le_bytes());
+            bytes.extend_from_slice(&(index.page_size() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(index.width.bytes() as u16).to_le_bytes());
+            index.visit_bitmaps(page_store, |_, bitmap| {
+                bytes.extend_from_slice(&bitmap.page_id.to_le_bytes());
+                bytes.extend_from_slice(bitmap.bits());
+            })
+        })?;
+        Ok(AllocatorStateBlob { bytes })
+    }
+
+    pub fn import_state(blob: &AllocatorStateBlob, page_store: &mut PageStore) -> Result<Pin<Box<IndexPage>>> {
+        let mut segments: Vec<Pin<Box<IndexPage>>> = Vec::new();
+        let mut offset = 0;
+        while offset < blob.bytes().len() {
+            let segment = &blob.bytes()[offset..];
+            if let Some(previous) = segments.last() {
+                let expected = previous.managed_end();
+                if expected != Some(get_u64(segment, 0)) || state_width(segment)? != previous.width {
+                    return invalid_data("invalid allocator state, chained index does not continue where the previous one ends");
+                }
+            }
+            let index = IndexPage::import_segment(segment, page_store.page_size())?;
+            offset += segment_size(index.current_bitmap_count, index.page_size(), index.width);
+            segments.push(index);
+        }
+
+        let mut index = match segments.pop() {
+            Some(index) => index,
+            None => return invalid_data("invalid allocator state, no index"),
+        };
+        while let Some(mut previous) = segments.pop() {
+            index.parent_page_id = previous.page_id;
+            previous.next_index_page_id = index.page_id;
+            previous.next = Some(index);
+            index = previous;
+        }
+
+        index.persist(page_store)?;
+        Ok(index)
+    }
+
+    fn import_segment(bytes: &[u8], page_size: usize) -> Result<Pin<Box<IndexPage>>> {
+        let first_managed_page_id = get_u64(bytes, 0);
+        let current_bitmap_count = get_u16(bytes, 16);
+        let state_page_size = get_u32(bytes, 18) as usize;
+        let width = state_width(bytes)?;
+        if state_page_size != page_size {
+            return invalid_data(
+                format!("invalid allocator state, {} byte pages cannot be imported into a store of {} byte pages", state_page_size, page_size)
+            );
+        }
+        if current_bitmap_count > index_bitmap_count(page_size, width) {
+            return invalid_data(format!("invalid allocator state, {} bitmaps exceed the index capacity", current_bitmap_count));
+        }
+        let pages_per_bitmap = bitmap_page_count(page_size, width) as u64;
+        let last_first_managed_page_id = (current_bitmap_count.saturating_sub(1) as u64 * pages_per_bitmap).checked_add(first_managed_page_id);
+        if last_first_managed_page_id.is_none_or(|last| last > max_first_managed_page_id(page_size, width)) {
+            return invalid_data(format!("invalid allocator state, bitmaps from page {} run beyond the last usable page", first_managed_page_id));
+        }
+
+        let mut index = Box::pin(IndexPage {
+            page_id: get_u64(bytes, 8),
+            first_managed_page_id,
+            width,
+            current_bitmap_count,
+            current_bitmap_idx: current_bitmap_count,
+            first_free_bitmap_idx: current_bitmap_count,
+            generation: 0,
+            parent_page_id: NO_PARENT,
+            next_index_page_id: NO_NEXT,
+            next: None,
+            reserved: Vec::new(),
+            dirty_bitmaps: HashMap::new(),
+            buffer: vec![0; page_size],
+        });
+        if bytes.len() < segment_size(current_bitmap_count, page_size, width) {
+            return invalid_data("invalid allocator state, truncated index");
+        }
+        let state_bitmap_size = segment_size(1, page_size, width) - STATE_HEADER_SIZE;
+        for idx in 0..current_bitmap_count {
+            let offset = STATE_HEADER_SIZE + idx as usize * state_bitmap_size;
+            let bitmap_first_managed_page_id = first_managed_page_id + idx as u64 * pages_per_bitmap;
+            let bits = &bytes[offset + 8..offset + state_bitmap_size];
+            let bitmap = BitmapPage::from_bits(get_u64(bytes, offset), bitmap_first_managed_page_id, page_size, width, bits)?;
+            index.update(&bitmap);
+            index.dirty_bitmaps.insert(idx, bitmap);
+        }
+        index.current_bitmap_idx = index.first_free_bitmap_idx;
+        Ok(index)
+    }
+
+    pub fn state_fingerprint(&self, page_store: &PageStore) -> Result<u64> {
+        let mut hash = FNV_OFFSET_BASIS;
+        self.visit_chain(page_store, &mut |index| {
+            hash = fnv1a(hash, &index.first_managed_page_id.to_le_bytes());
+            hash = fnv1a(hash, &index.current_bitmap_count.to_le_bytes());
+            index.visit_bitmaps(page_store, |_, bitmap| {
+                hash = fnv1a(hash, bitmap.bits());
+            })
+        })?;
+        Ok(hash)
+    }
+
+    pub(crate) fn metadata_pages(&self, page_store: &PageStore) -> Result<Vec<(u64, PageType, u64)>> {
+        let mut pages = Vec::new();
+        self.visit_chain(page_store, &mut |index| {
+            pages.extend(index.slots().map(|slot| {
+                let first_managed_page_id = index.first_managed_page_id + slot.bitmap_idx as u64 * index.pages_per_bitmap();
+                (slot.bitmap_page_id, index.width.bitmap_type(), first_managed_page_id)
+            }));
+            pages.push((index.page_id, index.width.index_type(), index.first_managed_page_id));
+            Ok(())
+        })?;
+        pages.sort_unstable_by_key(|(page_id, _, _)| *page_id);
+        Ok(pages)
+    }
+
+    // this index page only, chain_managed_pages includes the chained indexes. the bitmap and index pages that
+    // live inside the managed range are counted as used, so managed minus free includes the allocator's own pages
+    pub fn total_managed_pages(&self) -> u64 {
+        self.current_bitmap_count as u64 * self.pages_per_bitmap()
+    }
+
+    // this index page only, chain_free_pages includes the chained indexes
+    pub fn total_free_pages(&self) -> u64 {
+        self.slots().map(|slot| slot.free_page_count as u64).sum()
+    }
+
+    pub fn chain_managed_pages(&self, page_store: &PageStore) -> Result<u64> {
+        let mut managed_pages = 0;
+        self.visit_chain(page_store, &mut |index| {
+            managed_pages += index.total_managed_pages();
+            Ok(())
+        })?;
+        Ok(managed_pages)
+    }
+
+    pub fn chain_free_pages(&self, page_store: &PageStore) -> Result<u64> {
+        let mut free_pages = 0;
+        self.visit_chain(page_store, &mut |index| {
+            free_pages += index.total_free_pages();
+            Ok(())
+        })?;
+        Ok(free_pages)
+    }
+
+    pub fn bitmap_capacity(&self) -> (u16, u16) {
+        (self.current_bitmap_count, self.capacity())
+    }
+
+    pub fn is_at_capacity(&self) -> bool {
+        self.current_bitmap_count >= self.capacity()
+    }
+
+    pub fn relocate_bitmap_into_own_range(&mut self, bitmap_idx: u16, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<Option<u64>> {
+        if bitmap_idx >= self.current_bitmap_count {
+            return Ok(None);
+        }
+        if !self.dirty_bitmaps.contains_key(&bitmap_idx) {
+            let bitmap_page_id = self.slot_page_id(bitmap_idx);
+            let bitmap_memory = page_store.read_page(bitmap_page_id as usize)?;
+            let bitmap = BitmapPage::load_into(&bitmap_memory, bitmap_page_id)?;
+            self.dirty_bitmaps.insert(bitmap_idx, bitmap);
+        }
+
+        let bitmap = match self.dirty_bitmaps.get_mut(&bitmap_idx) {
+            Some(bitmap) => bitmap,
+            None => return Ok(None),
+        };
+        let old_page_id = bitmap.page_id;
+        if bitmap.contains(old_page_id) {
+            return Ok(Some(old_page_id));
+        }
+
+        let reserved = &self.reserved;
+        let new_page_id = match bitmap.allocate(|page_id| !is_reserved(reserved, page_id) && f(page_id)) {
+            Some(new_page_id) => new_page_id,
+            None => return Ok(None),
+        };
+        bitmap.page_id = new_page_id;
+        let free_page_count = bitmap.free_page_count;
+        self.update_bitmap_data(bitmap_idx, new_page_id, free_page_count);
+
+        if self.free(old_page_id, page_store, f)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(new_page_id))
+    }
+
+    pub fn reclaim_empty_bitmaps(&mut self, page_store: &PageStore, mut free_to: impl FnMut(u64)) -> Result<usize> {
+        let mut reclaimed = 0;
+        while self.current_bitmap_count > 1 {
+            let idx = self.current_bitmap_count - 1;
+            let pages_per_bitmap = self.pages_per_bitmap();
+            let bitmap_page_id = self.slot_page_id(idx);
+            let free_page_count = self.slot_free_page_count(idx);
+
+            let first_page_id = self.first_managed_page_id + idx as u64 * pages_per_bitmap;
+            let in_own_range = bitmap_page_id >= first_page_id && bitmap_page_id - first_page_id < pages_per_bitmap;
+            if free_page_count as u64 + in_own_range as u64 != pages_per_bitmap {
+                break;
+            }
+
+            self.dirty_bitmaps.remove(&idx);
+            self.update_bitmap_data(idx, 0, 0);
+            self.current_bitmap_count -= 1;
+            if self.first_free_bitmap_idx > self.current_bitmap_count {
+                self.first_free_bitmap_idx = self.current_bitmap_count;
+            }
+
+            if in_own_range {
+                free_to(bitmap_page_id);
+            } else if self.free(bitmap_page_id, page_store, &mut |_| true)?.is_none() {
+                return invalid_data(format!("bitmap {} page {} could not be freed", idx, bitmap_page_id));
+            }
+            reclaimed += 1;
+        }
+        Ok(reclaimed)
+    }
+
+    fn free_dirty(&mut self, page_id: u64) -> Option<bool> {
+        if page_id < self.first_managed_page_id {
+            return None;
+        }
+        let idx = ((page_id - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
 
         let bitmap = self.dirty_bitmaps.get_mut(&idx)?;
         let result = bitmap.free(page_id);
@@ -167,45 +960,62 @@ impl<'a> IndexPage {
         Some(result)
     }
 
-    fn free_unloaded(&mut self, page_id: u32, page_store: &PageStore, f: &mut impl FnMut(u32) -> bool) -> Option<bool> {
-        let new_bitmap_page_id = self.allocate(page_store, f)?;
-
-        let bitmap_idx = ((page_id - self.first_managed_page_id) / BITMAP_PAGE_COUNT as u32) as u16;
+    fn free_unloaded(&mut self, page_id: u64, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<Option<bool>> {
+        if page_id < self.first_managed_page_id {
+            return Ok(None);
+        }
+        let bitmap_idx = ((page_id - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
+        if !self.load_bitmap_for_update(bitmap_idx, page_store, f)? {
+            return Ok(None);
+        }
+        Ok(self.free_dirty(page_id))
+    }
 
-        let old_bitmap_page_id = get_u32(&self.buffer[INDEX_HEADER_SIZE..], bitmap_idx as usize * 4);
+    // loads the bitmap onto a newly allocated page, so its stored copy stays intact until the next persist
+    fn load_bitmap_for_update(&mut self, bitmap_idx: u16, page_store: &PageStore, f: &mut impl FnMut(u64) -> bool) -> Result<bool> {
+        if self.dirty_bitmaps.contains_key(&bitmap_idx) {
+            return Ok(true);
+        }
+        let new_bitmap_page_id = match self.allocate(page_store, f)? {
+            Some(new_bitmap_page_id) => new_bitmap_page_id,
+            None => return Ok(false),
+        };
+        if self.dirty_bitmaps.contains_key(&bitmap_idx) {
+            // allocating the new page already crossed into and loaded this bitmap
+            self.free_dirty(new_bitmap_page_id);
+            return Ok(true);
+        }
 
-        let bitmap_memory = page_store.read_page(old_bitmap_page_id as usize).ok()?;
+        let old_bitmap_page_id = self.slot_page_id(bitmap_idx);
 
-        let mut bitmap = BitmapPage::load_into(&bitmap_memory, new_bitmap_page_id);
+        let bitmap_memory = page_store.read_page(old_bitmap_page_id as usize)?;
 
-        let result = bitmap.free(page_id);
+        let bitmap = BitmapPage::load_into(&bitmap_memory, new_bitmap_page_id)?;
+        let freed = bitmap.contains(old_bitmap_page_id);
 
         self.update(&bitmap);
         self.dirty_bitmaps.insert(bitmap_idx, bitmap);
 
-        Some(result)
+        Ok(freed || self.free(old_bitmap_page_id, page_store, f)?.is_some())
     }
 
     fn update(&mut self, bitmap: &dyn BitmapHeader) {
-        let bitmap_idx = ((bitmap.first_managed_page_id() - self.first_managed_page_id) / BITMAP_PAGE_COUNT as u32) as u16;
+        let bitmap_idx = ((bitmap.first_managed_page_id() - self.first_managed_page_id) / self.pages_per_bitmap()) as u16;
 
         self.update_bitmap_data(bitmap_idx, bitmap.page_id(), bitmap.free_page_count())
     }
 
-    fn update_bitmap_data(&mut self, bitmap_idx: u16, page_id: u32, free_page_count: u16) {
-        let index = INDEX_HEADER_SIZE + (bitmap_idx * 4) as usize;
-
-        put_u32(&mut self.buffer, index, page_id);
-        put_u32(&mut self.buffer, index + INDEX_FREE_PAGE_OFFSET, free_page_count as u32);
+    fn update_bitmap_data(&mut self, bitmap_idx: u16, page_id: u64, free_page_count: u16) {
+        let header_size = index_header_size(self.width);
+        let free_page_offset = self.free_page_offset();
+        put_page_id(&mut self.buffer, header_size + bitmap_idx as usize * self.width.bytes(), self.width, page_id);
+        put_u32(&mut self.buffer, header_size + free_page_offset + bitmap_idx as usize * 4, free_page_count as u32);
 
         if bitmap_idx < self.first_free_bitmap_idx && free_page_count > 0 {
             self.first_free_bitmap_idx = bitmap_idx;
         } else if bitmap_idx == self.first_free_bitmap_idx && free_page_count == 0 {
             for idx in bitmap_idx + 1..self.current_bitmap_count {
-                let index = INDEX_HEADER_SIZE + INDEX_FREE_PAGE_OFFSET + idx as usize * 4;
-                let page_count = get_u32(&self.buffer, index);
-
-                if page_count > 0 {
+                if self.slot_free_page_count(idx) > 0 {
                     self.first_free_bitmap_idx = idx;
                     return;
                 }
@@ -215,6 +1025,62 @@ impl<'a> IndexPage {
     }
 }
 
+fn is_reserved(reserved: &[Range<u64>], page_id: u64) -> bool {
+    reserved.iter().any(|pages| pages.contains(&page_id))
+}
+
+fn state_width(segment: &[u8]) -> Result<PageIdWidth> {
+    match get_u16(segment, 22) {
+        4 => Ok(PageIdWidth::U32),
+        8 => Ok(PageIdWidth::U64),
+        bytes => invalid_data(format!("invalid allocator state, unsupported page id width of {} bytes", bytes)),
+    }
+}
+
+// an exported index followed by the page id and bits of each of its bitmaps
+fn segment_size(bitmap_count: u16, page_size: usize, width: PageIdWidth) -> usize {
+    STATE_HEADER_SIZE + bitmap_count as usize * (8 + bitmap_page_count(page_size, width) as usize / 8)
+}
+
+// pages covered by one index page, bitmap and index pages included
+pub fn pages_managed_per_index(page_size: usize, width: PageIdWidth) -> u64 {
+    index_bitmap_count(page_size, width) as u64 * bitmap_page_count(page_size, width) as u64
+}
+
+pub fn pages_required(data_pages: u64, page_size: usize, width: PageIdWidth) -> u16 {
+    let pages_per_bitmap = bitmap_page_count(page_size, width) as u64;
+    let mut bitmap_count: u16 = 2;
+    while (bitmap_count as u64 * pages_per_bitmap) < data_pages + bitmap_count as u64 + 1 {
+        bitmap_count += 1;
+    }
+    bitmap_count
+}
+
+pub fn required_max_size(index: &IndexPage, page_store: &PageStore) -> Result<usize> {
+    let estimate = index.compaction_savings(page_store)?;
+    let allocated = estimate.packed_high_water - index.first_managed_page_id;
+    // bitmap and index pages stored outside the managed range are not part of the allocated count
+    let managed_end = index.first_managed_page_id + index.total_managed_pages();
+    let in_range = |page_id: u64| page_id >= index.first_managed_page_id && page_id < managed_end;
+    let metadata_pages = index.slots().filter(|slot| in_range(slot.bitmap_page_id)).count() as u64
+        + in_range(index.page_id) as u64;
+    let data_pages = allocated.saturating_sub(metadata_pages);
+
+    let managed_pages = pages_required(data_pages, index.page_size(), index.width) as usize * index.pages_per_bitmap() as usize;
+    Ok((index.first_managed_page_id as usize + managed_pages) * page_store.page_size())
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn get_u16(buffer: &[u8], idx: usize) -> u16 {
+    u16::from_le_bytes([buffer[idx], buffer[idx + 1]])
+}
+
 pub fn get_u32(buffer: &[u8], idx: usize) -> u32 {
     let s = &buffer[idx..idx + 4];
     let mut a: [u8; 4] = [0; 4];
@@ -223,13 +1089,11 @@ pub fn get_u32(buffer: &[u8], idx: usize) -> u32 {
     u32::from_le_bytes(a)
 }
 
-fn put_u16(buffer: &mut [u8], idx: usize, value: u16) {
-    let bytes = value.to_le_bytes();
-    buffer[idx..idx + 2].clone_from_slice(&bytes);
-}
+fn get_u64(buffer: &[u8], idx: usize) -> u64 {
+    let mut a: [u8; 8] = [0; 8];
+    a.copy_from_slice(&buffer[idx..idx + 8]);
 
-fn put_u32(buffer: &mut [u8], idx: usize, value: u32) {
-    let bytes = value.to_le_bytes();
-    buffer[idx..idx + 4].clone_from_slice(&bytes);
+    u64::from_le_bytes(a)
 }
 
+
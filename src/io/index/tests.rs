@@ -1,14 +1,21 @@
-use crate::io::bitmap::{BitmapPage, BITMAP_PAGE_COUNT};
-use crate::io::index::IndexPage;
+use crate::io::bitmap::{bitmap_page_count, max_first_managed_page_id, AllocOutcome, BitmapPage, BITMAP_PAGE_COUNT, BitmapHeader, FreeHintStatus};
+use crate::io::index::{get_u32, AllocatorStateBlob, pages_managed_per_index, pages_required, put_u32, required_max_size, CompactionEstimate, IndexPage, IndexSlot, PersistCost, INDEX_BITMAP_COUNT, index_bitmap_count, index_header_size};
 use crate::io::store::PageStore;
+use crate::io::{PageIdWidth, PageType, MAX_PAGE_ID, PAGE_SIZE};
 use tempfile::tempfile;
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+const INDEX_HEADER_SIZE: usize = index_header_size(PageIdWidth::U32);
 
 #[test]
 fn grow_from_first_bitmap() {
-    let page = BitmapPage::new(2);
-    let index = IndexPage::grow(page);
+    let page = BitmapPage::new(2).unwrap();
+    let index = IndexPage::grow(page).unwrap();
 
-    assert_eq!(BITMAP_PAGE_COUNT as u32 + 3, index.page_id);
+    assert_eq!(BITMAP_PAGE_COUNT as u64 + 3, index.page_id);
     assert_eq!(2, index.first_managed_page_id);
     assert_eq!(2, index.current_bitmap_count);
     assert_eq!(1, index.current_bitmap_idx);
@@ -16,18 +23,26 @@ fn grow_from_first_bitmap() {
     assert_eq!(2, index.dirty_bitmaps.len());
 }
 
+#[test]
+fn bitmap_capacity_of_grown_index() {
+    let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    assert_eq!((2, INDEX_BITMAP_COUNT), index.bitmap_capacity());
+    assert!(!index.is_at_capacity());
+}
+
 #[test]
 fn cannot_load_index() {
     let mut store = temporary_store();
 
-    let page = BitmapPage::new(2);
-    let mut index = IndexPage::grow(page);
+    let page = BitmapPage::new(2).unwrap();
+    let mut index = IndexPage::grow(page).unwrap();
 
     index.persist(&mut store).unwrap();
 
-    let index_memory = store.read_page(1 + BITMAP_PAGE_COUNT as usize).unwrap();
+    let index_memory = store.read_page(3 + BITMAP_PAGE_COUNT as usize).unwrap();
 
-    let result = IndexPage::load(&index_memory, &store, |_| false);
+    let result = IndexPage::load(&index_memory, &store, |_| false).unwrap();
     assert!(result.is_none());
 }
 
@@ -35,14 +50,10 @@ fn cannot_load_index() {
 fn persist_and_load() {
     let mut store = temporary_store();
 
-    let page = BitmapPage::new(2);
-    let mut index = IndexPage::grow(page);
-
-    index.persist(&mut store).unwrap();
-
-    let index_memory = store.read_page(3 + BITMAP_PAGE_COUNT as usize).unwrap();
+    let page = BitmapPage::new(2).unwrap();
+    let index = IndexPage::grow(page).unwrap();
 
-    let loaded = IndexPage::load(&index_memory, &store, |_| true).unwrap();
+    let loaded = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
 
     assert_eq!(2, loaded.first_managed_page_id);
     assert_eq!(2, loaded.current_bitmap_count);
@@ -55,14 +66,10 @@ fn persist_and_load() {
 fn grow_on_load() {
     let mut store = temporary_store();
 
-    let page = BitmapPage::new(2);
-    let mut index = IndexPage::grow(page);
-
-    index.persist(&mut store).unwrap();
-
-    let index_memory = store.read_page(3 + BITMAP_PAGE_COUNT as usize).unwrap();
+    let page = BitmapPage::new(2).unwrap();
+    let index = IndexPage::grow(page).unwrap();
 
-    let loaded = IndexPage::load(&index_memory, &store, |x| x > 2 * BITMAP_PAGE_COUNT as u32 + 2).unwrap();
+    let loaded = index.persist_and_reload(&mut store, |x| x > 2 * BITMAP_PAGE_COUNT as u64 + 2).unwrap().unwrap();
 
     assert_eq!(2, loaded.first_managed_page_id);
     assert_eq!(3, loaded.current_bitmap_count);
@@ -71,16 +78,549 @@ fn grow_on_load() {
     assert_eq!(2, loaded.dirty_bitmaps.len());
 }
 
+#[test]
+fn cannot_load_torn_index() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+
+    let index_page_id = 3 + BITMAP_PAGE_COUNT as usize;
+    store.write_page_range(index_page_id, 16, &[0xFF; 4]).unwrap();
+
+    let index_memory = store.read_page(index_page_id).unwrap();
+    match IndexPage::load(&index_memory, &store, |_| true) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded a torn index")
+    }
+}
+
+#[test]
+fn parent_page_id_round_trips() {
+    let mut store = temporary_store();
+
+    let root = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let root = root.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    assert_eq!(None, root.parent_page_id());
+
+    let mut child = IndexPage::grow(BitmapPage::new(2 + 2 * BITMAP_PAGE_COUNT as u64).unwrap()).unwrap();
+    child.set_parent_page_id(Some(root.page_id));
+    let child = child.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    assert_eq!(Some(root.page_id), child.parent_page_id());
+}
+
+#[test]
+fn persist_and_reload_keeps_allocation_state() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let allocated = (0..5)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    let free_pages = slot_free_page_counts(&index);
+
+    let mut reloaded = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+
+    assert_eq!(2, reloaded.first_managed_page_id);
+    assert_eq!(2, reloaded.current_bitmap_count);
+    assert_eq!(free_pages.iter().sum::<u32>(), slot_free_page_counts(&reloaded).iter().sum::<u32>());
+    for _ in 0..100 {
+        let page = reloaded.allocate(&store, &mut |_| true).unwrap().unwrap();
+        assert!(!allocated.contains(&page));
+    }
+}
+
+#[test]
+fn allocates_in_emptiest_bitmap() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    for _ in 0..5 {
+        assert!(index.allocate(&store, &mut |_| true).unwrap().unwrap() > 2 + BITMAP_PAGE_COUNT as u64);
+    }
+
+    let page = index.allocate_in_emptiest(&store, &mut |_| true).unwrap().unwrap();
+
+    assert_eq!(3, page);
+    assert_eq!(0, index.current_bitmap_idx);
+}
+
+#[test]
+fn refresh_cursor_reconsiders_pages_rejected_at_load() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+
+    let memory = store.read_page(index.page_id as usize).unwrap();
+    let mut loaded = IndexPage::load(&memory, &store, |page_id| page_id != 3).unwrap().unwrap();
+    assert_eq!(Some(2), loaded.first_free_page(&store));
+
+    assert!(loaded.refresh_cursor(&store, &mut |_| true).unwrap());
+    assert_eq!(2, loaded.allocate(&store, &mut |_| true).unwrap().unwrap());
+    assert_eq!(3, loaded.allocate(&store, &mut |_| true).unwrap().unwrap());
+}
+
+#[test]
+fn cannot_load_unknown_index_version() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+    store.write_page_range(index.page_id as usize, 24, &[0xFF, 0xFF]).unwrap();
+
+    let index_memory = store.read_page(index.page_id as usize).unwrap();
+    match IndexPage::load(&index_memory, &store, |_| true) {
+        Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+        Ok(_) => panic!("should not have loaded an unknown index version")
+    }
+}
+
+#[test]
+fn validates_coverage_of_healthy_index() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+
+    index.validate_coverage(&store).unwrap();
+}
+
+#[test]
+fn validate_coverage_reports_gap() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+
+    let wrong_base = 3 + BITMAP_PAGE_COUNT as u64;
+    store.write_page_range(2 + BITMAP_PAGE_COUNT as usize, 8, &wrong_base.to_le_bytes()).unwrap();
+
+    match index.validate_coverage(&store) {
+        Err(e) => {
+            assert_eq!(ErrorKind::InvalidData, e.kind());
+            assert!(e.to_string().starts_with("bitmap 1 "));
+        }
+        Ok(_) => panic!("should have reported the gap")
+    }
+}
+
+#[test]
+fn loads_bitmaps_lazily() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+
+    let bitmaps = index.bitmaps_lazy(&store)
+        .map(|bitmap| {
+            let bitmap = bitmap.unwrap();
+            (bitmap.first_managed_page_id(), bitmap.free_page_count())
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(vec![
+        (2, BITMAP_PAGE_COUNT - 1),
+        (2 + BITMAP_PAGE_COUNT as u64, BITMAP_PAGE_COUNT - 2),
+    ], bitmaps);
+}
+
+#[test]
+fn compaction_savings_of_fragmented_store() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let pages = (0..10)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    index.free(pages[2], &store, &mut |_| true).unwrap().unwrap();
+    index.free(pages[5], &store, &mut |_| true).unwrap().unwrap();
+
+    let high_water = 14 + BITMAP_PAGE_COUNT as u64;
+    let allocated = 3 + 8;
+    assert_eq!(CompactionEstimate {
+        current_high_water: high_water,
+        packed_high_water: 2 + allocated,
+        pages_reclaimable: high_water - 2 - allocated,
+    }, index.compaction_savings(&store).unwrap());
+}
+
+#[test]
+fn compacts_into_store_of_required_max_size() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let pages = (0..10)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    index.free(pages[2], &store, &mut |_| true).unwrap().unwrap();
+    index.free(pages[5], &store, &mut |_| true).unwrap().unwrap();
+
+    let max_size = required_max_size(&index, &store).unwrap();
+    assert_eq!((2 + 2 * BITMAP_PAGE_COUNT as usize) * 4096, max_size);
+
+    let mut destination = PageStore::new(tempfile().unwrap(), max_size).unwrap();
+    let mut compacted = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    for _ in 0..8 {
+        let page = compacted.allocate(&destination, &mut |_| true).unwrap().unwrap();
+        destination.write_page(page as usize, &[0; 4096]).unwrap();
+    }
+    compacted.persist(&mut destination).unwrap();
+}
+
+#[test]
+fn required_max_size_ignores_bitmaps_outside_their_range() {
+    let store = temporary_store();
+    // only the second bitmap and the index page are allocated inside the managed range
+    let index = IndexPage::grow(BitmapPage::new_external(1, 2).unwrap()).unwrap();
+
+    let max_size = required_max_size(&index, &store).unwrap();
+
+    assert_eq!((2 + 2 * BITMAP_PAGE_COUNT as usize) * 4096, max_size);
+}
+
+#[test]
+fn cannot_grow_past_max_page_id() {
+    let bitmap = BitmapPage::new(max_first_managed_page_id(PAGE_SIZE, PageIdWidth::U32)).unwrap();
+
+    assert_eq!(ErrorKind::InvalidInput, IndexPage::grow(bitmap).err().unwrap().kind());
+}
+
+#[test]
+fn stops_growing_at_max_page_id() {
+    let mut index = IndexPage::grow(BitmapPage::new(max_first_managed_page_id(PAGE_SIZE, PageIdWidth::U32) - 2 * BITMAP_PAGE_COUNT as u64).unwrap()).unwrap();
+
+    assert!(index.grow_next_bitmap());
+    assert!(!index.grow_next_bitmap());
+    assert_eq!(3, index.current_bitmap_count);
+}
+
+#[test]
+fn requires_more_bitmaps_for_large_stores() {
+    assert_eq!(2, pages_required(0, PAGE_SIZE, PageIdWidth::U32));
+    assert_eq!(2, pages_required(2 * BITMAP_PAGE_COUNT as u64 - 3, PAGE_SIZE, PageIdWidth::U32));
+    assert_eq!(3, pages_required(2 * BITMAP_PAGE_COUNT as u64 - 2, PAGE_SIZE, PageIdWidth::U32));
+}
+
+#[test]
+fn exports_and_imports_allocator_state() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+    let pages = (0..10)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    index.free(pages[2], &store, &mut |_| true).unwrap().unwrap();
+    index.free(pages[5], &store, &mut |_| true).unwrap().unwrap();
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.clear();
+
+    let blob = index.export_state(&store).unwrap();
+    let blob = AllocatorStateBlob::from_bytes(blob.bytes().to_vec()).unwrap();
+
+    let mut replica = temporary_store();
+    let imported = IndexPage::import_state(&blob, &mut replica).unwrap();
+
+    assert_eq!(index.page_id, imported.page_id);
+    assert_eq!(index.first_free_bitmap_idx, imported.first_free_bitmap_idx);
+    assert_eq!(slot_free_page_counts(&index), slot_free_page_counts(&imported));
+    assert_eq!(blob.bytes(), imported.export_state(&replica).unwrap().bytes());
+
+    let memory = replica.read_page(imported.page_id as usize).unwrap();
+    let mut reloaded = IndexPage::load(&memory, &replica, |_| true).unwrap().unwrap();
+    let page = reloaded.allocate(&replica, &mut |_| true).unwrap().unwrap();
+    assert!(!pages.contains(&page) || page == pages[2] || page == pages[5]);
+}
+
+#[test]
+fn rejects_truncated_allocator_state() {
+    assert_eq!(ErrorKind::InvalidData, AllocatorStateBlob::from_bytes(vec![0; 12]).err().unwrap().kind());
+}
+
+#[test]
+fn state_fingerprint_tracks_allocation_state() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let initial = index.state_fingerprint(&store).unwrap();
+
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.clear();
+    assert_eq!(initial, index.state_fingerprint(&store).unwrap());
+
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    let allocated = index.state_fingerprint(&store).unwrap();
+    assert_ne!(initial, allocated);
+
+    let other = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    index.free(other, &store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(allocated, index.state_fingerprint(&store).unwrap());
+
+    index.free(page, &store, &mut |_| true).unwrap().unwrap();
+    assert_ne!(allocated, index.state_fingerprint(&store).unwrap());
+}
+
+#[test]
+fn persist_cost_of_scattered_bitmaps() {
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    assert!(index.grow_next_bitmap());
+
+    assert_eq!(PersistCost {
+        dirty_bitmap_writes: 3,
+        index_writes: 1,
+        contiguous_runs: 3,
+        estimated_syscalls: 4,
+    }, index.persist_cost());
+}
+
+#[test]
+fn persists_only_changed_bitmaps() {
+    let mut store = temporary_store();
+    let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let mut index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    index.persist(&mut store).unwrap();
+    assert_eq!(0, index.persist_cost().dirty_bitmap_writes);
+    let pages = |index: &IndexPage, store: &PageStore| index.slots()
+        .map(|slot| store.read_page(slot.bitmap_page_id as usize).unwrap().content().to_vec())
+        .collect::<Vec<_>>();
+    let before = pages(&index, &store);
+
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    let changed = ((page - 2) / BITMAP_PAGE_COUNT as u64) as usize;
+    assert_eq!(1, index.persist_cost().dirty_bitmap_writes);
+    index.persist(&mut store).unwrap();
+
+    let after = pages(&index, &store);
+    for idx in 0..before.len() {
+        assert_eq!(idx != changed, before[idx] == after[idx], "bitmap {}", idx);
+    }
+}
+
+#[test]
+fn persist_reports_bitmap_that_failed() {
+    let file = tempfile().unwrap();
+    let mut store = PageStore::new(file, 3 * 4096).unwrap();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    let error = index.persist(&mut store).unwrap_err();
+
+    assert!(error.to_string().starts_with(&format!("bitmap 1 (page {})", 2 + BITMAP_PAGE_COUNT as u64)), "{}", error);
+    assert!(!index.dirty_bitmaps[&0].is_dirty());
+    assert!(index.dirty_bitmaps[&1].is_dirty());
+    assert_eq!(2, store.read_page(2).unwrap().page_id());
+}
+
+#[test]
+fn persist_cost_of_contiguous_bitmaps() {
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    assert!(index.grow_next_bitmap());
+    for (idx, bitmap) in index.dirty_bitmaps.iter_mut() {
+        bitmap.page_id = 10 + *idx as u64;
+    }
+    index.page_id = 13;
+
+    assert_eq!(1, index.persist_cost().contiguous_runs);
+    assert_eq!(2, index.persist_cost().estimated_syscalls);
+}
+
+#[test]
+fn relocates_bitmap_into_own_range() {
+    let mut store = temporary_store();
+
+    let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let mut index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    let old_page_id = index.dirty_bitmaps[&1].page_id;
+    let free_page_count = index.dirty_bitmaps[&0].free_page_count;
+    assert!(index.dirty_bitmaps[&0].contains(old_page_id));
+
+    let new_page_id = index.relocate_bitmap_into_own_range(1, &store, &mut |_| true).unwrap().unwrap();
+
+    assert!(index.dirty_bitmaps[&1].contains(new_page_id));
+    assert_eq!(new_page_id, index.dirty_bitmaps[&1].page_id);
+    assert_eq!(new_page_id, index.slot_page_id(1));
+    assert_eq!(free_page_count + 1, index.dirty_bitmaps[&0].free_page_count);
+}
+
+#[test]
+fn relocating_bitmap_already_in_own_range_keeps_page() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    assert_eq!(Some(2), index.relocate_bitmap_into_own_range(0, &store, &mut |_| true).unwrap());
+}
+
+#[test]
+fn verify_reports_drifted_free_counts() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.allocate(&store, &mut |_| true).unwrap();
+    index.persist(&mut store).unwrap();
+    assert!(index.verify(&store).unwrap().is_empty());
+
+    let bitmap_page = 2 + BITMAP_PAGE_COUNT as usize;
+    store.write_page_range(bitmap_page, 12, &7u16.to_le_bytes()).unwrap();
+    index.dirty_bitmaps.remove(&1);
+    assert_eq!(vec![(index.page_id, 1)], index.verify(&store).unwrap());
+
+    let free_page_offset = index.free_page_offset();
+    put_u32(&mut index.buffer, INDEX_HEADER_SIZE + free_page_offset, 7);
+    assert_eq!(vec![(index.page_id, 0), (index.page_id, 1)], index.verify(&store).unwrap());
+}
+
+#[test]
+fn audit_flags_drifted_free_hint() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+    assert_eq!(vec![(0, FreeHintStatus::Ok), (1, FreeHintStatus::Ok)], index.audit_free_hints(&store).unwrap());
+
+    let bitmap_page = 2 + BITMAP_PAGE_COUNT as usize;
+    store.write_page_range(bitmap_page, 14, &0u16.to_le_bytes()).unwrap();
+    index.dirty_bitmaps.remove(&1);
+
+    assert_eq!(vec![(0, FreeHintStatus::Ok), (1, FreeHintStatus::PointsAtUsed)], index.audit_free_hints(&store).unwrap());
+}
+
+#[test]
+fn iterates_slots_of_grown_index() {
+    let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    assert_eq!(vec![
+        IndexSlot { bitmap_idx: 0, bitmap_page_id: 2, free_page_count: BITMAP_PAGE_COUNT as u32 - 1 },
+        IndexSlot { bitmap_idx: 1, bitmap_page_id: 2 + BITMAP_PAGE_COUNT as u64, free_page_count: BITMAP_PAGE_COUNT as u32 - 2 },
+    ], index.slots().collect::<Vec<_>>());
+}
+
+#[test]
+fn finds_no_duplicate_slots_in_healthy_index() {
+    let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    assert!(index.find_duplicate_bitmap_slots().is_empty());
+}
+
+#[test]
+fn finds_duplicate_bitmap_slots() {
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    put_u32(&mut index.buffer, INDEX_HEADER_SIZE + 4, 2);
+
+    assert_eq!(vec![(0, 1)], index.find_duplicate_bitmap_slots());
+}
+
+#[test]
+fn total_managed_pages_covers_allocated_and_free_pages() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    for _ in 0..5 {
+        index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    }
+
+    assert_eq!(2 * BITMAP_PAGE_COUNT as u64, index.total_managed_pages());
+    // the two bitmap pages and the index page are used pages of the managed range
+    assert_eq!(3 + 5, index.total_managed_pages() - index.total_free_pages());
+}
+
+#[test]
+fn total_free_pages_tracks_allocations_and_frees() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let before = index.total_free_pages();
+    let first = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    index.allocate(&store, &mut |_| true).unwrap().unwrap();
+
+    assert_eq!(before - 3, index.total_free_pages());
+
+    index.free(first, &store, &mut |_| true).unwrap().unwrap();
+
+    assert_eq!(before - 2, index.total_free_pages());
+}
+
+#[test]
+fn reclaims_trailing_empty_bitmap() {
+    let store = temporary_store();
+
+    let mut page = BitmapPage::new(2).unwrap();
+    for _ in 1..BITMAP_PAGE_COUNT {
+        page.allocate(|_| true).unwrap();
+    }
+    let mut index = IndexPage::grow(page).unwrap();
+    for _ in 2..BITMAP_PAGE_COUNT {
+        index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    }
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(3, index.current_bitmap_count);
+    index.free(page, &store, &mut |_| true).unwrap().unwrap();
+
+    let mut offered = Vec::new();
+    assert_eq!(1, index.reclaim_empty_bitmaps(&store, |page_id| offered.push(page_id)).unwrap());
+
+    assert_eq!(vec![2 + 2 * BITMAP_PAGE_COUNT as u64], offered);
+    assert_eq!(2, index.current_bitmap_count);
+    assert_eq!(2, index.first_free_bitmap_idx);
+    assert_eq!(0, index.reclaim_empty_bitmaps(&store, |_| panic!("nothing to reclaim")).unwrap());
+}
+
+#[test]
+fn first_free_page_finds_freed_low_page() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+    let pages = (0..10)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(Some(13), index.first_free_page(&store));
+
+    index.free(pages[4], &store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(Some(pages[4]), index.first_free_page(&store));
+
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.clear();
+    assert_eq!(Some(pages[4]), index.first_free_page(&store));
+}
+
+#[test]
+fn allocate_propagates_read_error_of_next_bitmap() {
+    let store = temporary_store();
+
+    let mut page = BitmapPage::new(2).unwrap();
+    for _ in 2..BITMAP_PAGE_COUNT {
+        page.allocate(|_| true).unwrap();
+    }
+    let mut index = IndexPage::grow(page).unwrap();
+    index.current_bitmap_idx = 0;
+    index.dirty_bitmaps.remove(&1);
+    put_u32(&mut index.buffer, INDEX_HEADER_SIZE + 4, 1000);
+
+    assert!(index.allocate(&store, &mut |_| true).unwrap().is_some());
+    assert_eq!(ErrorKind::InvalidInput, index.allocate(&store, &mut |_| true).unwrap_err().kind());
+}
+
+#[test]
+fn cannot_grow_past_u32_page_ids() {
+    let first_managed_page_id = u32::MAX as u64 - 2 * BITMAP_PAGE_COUNT as u64 - 10;
+    let mut index = IndexPage::grow(BitmapPage::new(first_managed_page_id).unwrap()).unwrap();
+
+    assert!(!index.grow_next_bitmap());
+    assert_eq!(2, index.current_bitmap_count);
+}
+
 #[test]
 fn allocate_and_free() {
     let store = temporary_store();
 
-    let page = BitmapPage::new(2);
-    let mut index = IndexPage::grow(page);
+    let page = BitmapPage::new(2).unwrap();
+    let mut index = IndexPage::grow(page).unwrap();
 
-    let page = index.allocate(&store, &mut |_| true).unwrap();
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
 
-    let freed = index.free(page, &store, &mut |_| true).unwrap();
+    let freed = index.free(page, &store, &mut |_| true).unwrap().unwrap();
 
     assert!(freed);
 }
@@ -89,15 +629,15 @@ fn allocate_and_free() {
 fn free_on_full_bitmap() {
     let store = temporary_store();
 
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     for _ in 1..BITMAP_PAGE_COUNT {
         page.allocate(|_| true).unwrap();
     }
 
-    let mut index = IndexPage::grow(page);
+    let mut index = IndexPage::grow(page).unwrap();
     assert_eq!(1, index.first_free_bitmap_idx);
 
-    let freed = index.free(3, &store, &mut |_| true).unwrap();
+    let freed = index.free(3, &store, &mut |_| true).unwrap().unwrap();
     assert!(freed);
 }
 
@@ -105,15 +645,15 @@ fn free_on_full_bitmap() {
 fn allocate_full_bitmap() {
     let store = temporary_store();
 
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     for _ in 2..BITMAP_PAGE_COUNT {
         page.allocate(|_| true).unwrap();
     }
 
-    let mut index = IndexPage::grow(page);
+    let mut index = IndexPage::grow(page).unwrap();
     index.current_bitmap_idx = 0;
 
-    index.allocate(&store, &mut |_| true).unwrap();
+    index.allocate(&store, &mut |_| true).unwrap().unwrap();
     assert_eq!(1, index.first_free_bitmap_idx);
     assert_eq!(0, index.current_bitmap_idx);
 }
@@ -122,31 +662,471 @@ fn allocate_full_bitmap() {
 fn allocate_two_full_bitmaps() {
     let store = temporary_store();
 
-    let mut page = BitmapPage::new(2);
+    let mut page = BitmapPage::new(2).unwrap();
     for _ in 1..BITMAP_PAGE_COUNT {
         page.allocate(|_| true).unwrap();
     }
 
-    let mut index = IndexPage::grow(page);
+    let mut index = IndexPage::grow(page).unwrap();
     for _ in 2..BITMAP_PAGE_COUNT {
-        index.allocate(&store, &mut |_| true).unwrap();
+        index.allocate(&store, &mut |_| true).unwrap().unwrap();
     }
     assert_eq!(2, index.first_free_bitmap_idx);
     assert_eq!(1, index.current_bitmap_idx);
 
-    let freed = index.free(3 + BITMAP_PAGE_COUNT as u32, &store, &mut |_| true).unwrap();
+    let freed = index.free(3 + BITMAP_PAGE_COUNT as u64, &store, &mut |_| true).unwrap().unwrap();
     assert!(freed);
 
     assert_eq!(1, index.first_free_bitmap_idx);
     assert_eq!(1, index.current_bitmap_idx);
 
-    let page = index.allocate(&store, &mut |_| true).unwrap();
-    assert_ne!(3 + BITMAP_PAGE_COUNT as u32, page);
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_ne!(3 + BITMAP_PAGE_COUNT as u64, page);
     assert_eq!(2, index.current_bitmap_idx);
 }
 
+#[test]
+fn allocate_recovers_from_unloaded_cursor() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 7;
+
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(3, page);
+    assert_eq!(0, index.current_bitmap_idx);
+}
+
+#[test]
+fn allocate_crosses_into_unloaded_bitmap_after_load() {
+    let mut store = temporary_store();
+
+    let mut page = BitmapPage::new(2).unwrap();
+    for _ in 5..BITMAP_PAGE_COUNT {
+        page.allocate(|_| true).unwrap();
+    }
+    let index = IndexPage::grow(page).unwrap();
+    let mut loaded = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    loaded.persist(&mut store).unwrap();
+    loaded.dirty_bitmaps.remove(&1);
+    assert_eq!(0, loaded.current_bitmap_idx);
+
+    let mut page = loaded.allocate(&store, &mut |_| true).unwrap().unwrap();
+    while page < 2 + BITMAP_PAGE_COUNT as u64 {
+        page = loaded.allocate(&store, &mut |_| true).unwrap().unwrap();
+    }
+
+    assert_eq!(1, loaded.current_bitmap_idx);
+    assert!(loaded.dirty_bitmaps.contains_key(&1));
+}
+
+#[test]
+fn loads_index_when_first_bitmap_runs_full_during_load() {
+    let mut store = temporary_store();
+
+    let mut page = BitmapPage::new(2).unwrap();
+    for _ in 3..BITMAP_PAGE_COUNT {
+        page.allocate(|_| true).unwrap();
+    }
+    let index = IndexPage::grow(page).unwrap();
+    let mut loaded = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+
+    assert_eq!(2, loaded.dirty_bitmaps.len());
+    loaded.persist(&mut store).unwrap();
+    loaded.validate_coverage(&store).unwrap();
+}
+
+#[test]
+fn activate_next_bitmap_skips_full_slots() {
+    let mut store = temporary_store();
+
+    let mut page = BitmapPage::new(2).unwrap();
+    while page.allocate(|_| true).is_some() {}
+    let mut index = IndexPage::grow(page).unwrap();
+    while index.current_bitmap_count < 3 {
+        index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    }
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.remove(&2);
+    assert_eq!(vec![0, 0], slot_free_page_counts(&index)[..2].to_vec());
+
+    assert!(index.activate_next_bitmap(&store, 0, &mut |_| true).unwrap());
+
+    assert_eq!(2, index.current_bitmap_idx);
+    assert_eq!(2 + 2 * BITMAP_PAGE_COUNT as u64, index.dirty_bitmaps[&2].first_managed_page_id());
+}
+
+#[test]
+fn allocate_reuses_loaded_dirty_bitmap() {
+    let store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+    assert_eq!(3, index.allocate(&store, &mut |_| true).unwrap().unwrap());
+    index.current_bitmap_idx = index.current_bitmap_count;
+
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(4, page);
+    assert_eq!(0, index.current_bitmap_idx);
+}
+
+#[test]
+fn grows_and_reloads_on_larger_pages() {
+    let page_size = 16384;
+    let pages_per_bitmap = 0xFFF8;
+    let file = tempfile().unwrap();
+    let mut store = PageStore::with_page_size(file, 3 * pages_per_bitmap * page_size, page_size).unwrap();
+    let mut index = IndexPage::grow(BitmapPage::with_page_size(2, 2, page_size).unwrap()).unwrap();
+
+    assert_eq!((2, index_bitmap_count(page_size, PageIdWidth::U32)), index.bitmap_capacity());
+    assert_eq!(2 * pages_per_bitmap as u64, index.total_managed_pages());
+    assert_eq!(2 + pages_per_bitmap as u64 + 1, index.page_id);
+
+    let first = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    let mut index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    let second = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(Some(true), index.free(first, &store, &mut |_| true).unwrap());
+    assert!(index.verify(&store).unwrap().is_empty());
+
+    let blob = AllocatorStateBlob::from_bytes(index.export_state(&store).unwrap().bytes().to_vec()).unwrap();
+    let imported = IndexPage::import_state(&blob, &mut store).unwrap();
+    assert_eq!(index.state_fingerprint(&store).unwrap(), imported.state_fingerprint(&store).unwrap());
+
+    let mut small_store = temporary_store();
+    assert_eq!(ErrorKind::InvalidData, IndexPage::import_state(&blob, &mut small_store).err().unwrap().kind());
+}
+
+#[test]
+fn grows_and_reloads_with_u64_page_ids() {
+    let mut store = temporary_store();
+    let pages_per_bitmap = bitmap_page_count(PAGE_SIZE, PageIdWidth::U64) as u64;
+    let mut index = IndexPage::grow(BitmapPage::with_layout(2, 2, PAGE_SIZE, PageIdWidth::U64).unwrap()).unwrap();
+    assert_eq!((2, index_bitmap_count(PAGE_SIZE, PageIdWidth::U64)), index.bitmap_capacity());
+    assert_eq!(2 + pages_per_bitmap + 1, index.page_id);
+
+    index.set_parent_page_id(Some(6_000_000_000));
+    let first = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    index.persist(&mut store).unwrap();
+
+    let memory = store.read_page(index.page_id as usize).unwrap();
+    assert_eq!(Some(PageType::WideIndex), memory.typed());
+    let view = IndexPage::view(&memory).unwrap();
+    assert_eq!(PageIdWidth::U64, view.id_width());
+    assert_eq!(Some(6_000_000_000), view.parent_page_id());
+    assert_eq!(None, view.next_index_page_id());
+    assert_eq!(index.slots().collect::<Vec<_>>(), view.slots().collect::<Vec<_>>());
+
+    let mut index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+    index.persist(&mut store).unwrap();
+    assert_eq!(Some(true), index.is_allocated(first, &store).unwrap());
+    assert!(index.verify(&store).unwrap().is_empty());
+    let report = store.verify_streaming(&index, false).unwrap();
+    assert!(report.is_clean(), "{:?}", report.problems);
+
+    let blob = AllocatorStateBlob::from_bytes(index.export_state(&store).unwrap().bytes().to_vec()).unwrap();
+    let mut replica = temporary_store();
+    let imported = IndexPage::import_state(&blob, &mut replica).unwrap();
+    assert_eq!(PageIdWidth::U64, imported.id_width());
+    assert_eq!(index.state_fingerprint(&store).unwrap(), imported.state_fingerprint(&replica).unwrap());
+}
+
+#[test]
+fn allocates_beyond_max_page_id_with_u64_page_ids() {
+    let store = temporary_store();
+    let first_managed_page_id = MAX_PAGE_ID as u64 - 10;
+    let mut index = IndexPage::grow(BitmapPage::with_layout(first_managed_page_id, first_managed_page_id, PAGE_SIZE, PageIdWidth::U64).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+
+    let pages = (0..20).map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap()).collect::<Vec<_>>();
+    assert_eq!((first_managed_page_id + 1..first_managed_page_id + 21).collect::<Vec<_>>(), pages);
+    assert!(pages[19] > u32::MAX as u64);
+
+    assert_eq!(Some(true), index.free(pages[19], &store, &mut |_| true).unwrap());
+    assert_eq!(Some(false), index.is_allocated(pages[19], &store).unwrap());
+}
+
+#[test]
+fn reserved_range_is_never_allocated() {
+    let mut store = temporary_store();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.clear();
+
+    assert!(index.reserve(10..50, &store).unwrap());
+    let pages = (0..500)
+        .map(|_| index.allocate(&store, &mut |_| true).unwrap().unwrap())
+        .collect::<Vec<_>>();
+
+    assert!(pages.iter().all(|page| !(10..50).contains(page)));
+    assert!((10..50).all(|page| index.is_allocated(page, &store).unwrap() == Some(true)));
+
+    assert_eq!(Some(true), index.free(20, &store, &mut |_| true).unwrap());
+    let page = index.allocate_in_emptiest(&store, &mut |page| page < 100).unwrap();
+    assert_ne!(Some(20), page);
+}
+
+#[test]
+fn cannot_reserve_beyond_bitmaps() {
+    let store = temporary_store();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let end = 2 + 2 * BITMAP_PAGE_COUNT as u64;
+
+    assert!(!index.reserve(end - 1..end + 1, &store).unwrap());
+    assert!(!index.reserve(1..3, &store).unwrap());
+    assert!(index.reserve(end - 1..end, &store).unwrap());
+}
+
+#[test]
+fn budget_is_shared_across_bitmaps() {
+    let store = temporary_store();
+    let second_bitmap = 2 + BITMAP_PAGE_COUNT as u64;
+    let bitmap_bytes = BITMAP_PAGE_COUNT as u32 / 8;
+
+    // the filter rejects every page of the first bitmap, so all of its bytes are scanned
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+    let outcome = index.allocate_with_budget(&store, bitmap_bytes, &mut |page_id| page_id >= second_bitmap).unwrap();
+    assert_eq!(AllocOutcome::BudgetExhausted, outcome);
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.current_bitmap_idx = 0;
+    let outcome = index.allocate_with_budget(&store, bitmap_bytes + 1, &mut |page_id| page_id >= second_bitmap).unwrap();
+    assert_eq!(AllocOutcome::Allocated(second_bitmap + 2), outcome);
+}
+
+#[test]
+fn prefetches_next_bitmap_when_current_runs_low() {
+    let mut store = temporary_store();
+    let prefetched = Arc::new(Mutex::new(Vec::new()));
+    let recorder = prefetched.clone();
+    store.set_prefetch_hook(move |pages| recorder.lock().unwrap().extend(pages));
+
+    let mut page = BitmapPage::new(2).unwrap();
+    assert!(page.reserve(3..BITMAP_PAGE_COUNT as u64 - 64));
+    let mut index = IndexPage::grow(page).unwrap();
+    index.persist(&mut store).unwrap();
+    index.dirty_bitmaps.remove(&1);
+    index.current_bitmap_idx = 0;
+
+    index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert!(prefetched.lock().unwrap().is_empty());
+
+    index.allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_eq!(vec![2 + BITMAP_PAGE_COUNT as usize], *prefetched.lock().unwrap());
+}
+
+#[test]
+fn random_allocate_free_sequences_match_reference_model() {
+    let mut store = temporary_store();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let mut model: BTreeSet<u64> = BTreeSet::new();
+    let mut seed = 0x2545_F491_4F6C_DD1Du64;
+
+    // only a few hundred pages stay free in the first two bitmaps, so runs activate, grow and reload across bitmaps
+    let second = 2 + BITMAP_PAGE_COUNT as u64;
+    let reserved = [200..second - 100, second + 200..second + BITMAP_PAGE_COUNT as u64 - 100];
+    for pages in reserved.iter() {
+        assert!(index.reserve(pages.clone(), &store).unwrap());
+    }
+    let reserved_count = reserved.iter().map(|pages| pages.end - pages.start).sum::<u64>();
+
+    for step in 0..5000 {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+
+        let metadata = (0..index.current_bitmap_count)
+            .map(|idx| index.slot_page_id(idx))
+            .chain(Some(index.page_id))
+            .collect::<BTreeSet<_>>();
+
+        match seed % 100 {
+            0..=59 => {
+                let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+                assert!(!reserved.iter().any(|pages| pages.contains(&page)), "step {}: reserved page {} handed out", step, page);
+                assert!(model.insert(page), "step {}: page {} handed out twice", step, page);
+            }
+            60..=91 => {
+                if let Some(&page) = model.iter().nth((seed >> 32) as usize % model.len().max(1)) {
+                    assert_eq!(Some(true), index.free(page, &store, &mut |_| true).unwrap(), "step {}: page {}", step, page);
+                    model.remove(&page);
+                }
+            }
+            92..=96 => {
+                let page = model.iter().nth((seed >> 32) as usize % model.len().max(1)).map_or(2, |page| page + 1);
+                let unowned = !model.contains(&page) && !metadata.contains(&page) && !reserved.iter().any(|pages| pages.contains(&page));
+                if unowned {
+                    assert_eq!(Some(false), index.is_allocated(page, &store).unwrap(), "step {}: page {}", step, page);
+                    index.free(page, &store, &mut |_| true).unwrap();
+                    assert_eq!(Some(false), index.is_allocated(page, &store).unwrap(), "step {}: page {}", step, page);
+                }
+            }
+            97..=98 => index.persist(&mut store).unwrap(),
+            _ => index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap(),
+        }
+
+        let bitmap_count = index.current_bitmap_count as u64;
+        assert_eq!(index.total_managed_pages() - model.len() as u64 - reserved_count - bitmap_count - 1, index.total_free_pages(), "step {}", step);
+        assert!(!model.contains(&index.page_id), "step {}", step);
+        for idx in 0..index.current_bitmap_count {
+            assert!(!model.contains(&index.slot_page_id(idx)), "step {}", step);
+        }
+    }
+    assert!(index.current_bitmap_count > 2);
+}
+
+#[test]
+fn saturated_index_chains_to_next_index() {
+    let store = temporary_store();
+    let mut index = saturated_index();
+    let chained_first_page_id = 2 + pages_managed_per_index(PAGE_SIZE, PageIdWidth::U32);
+
+    let page = index.allocate(&store, &mut |_| true).unwrap().unwrap();
+
+    let next_index_page_id = index.next_index_page_id().unwrap();
+    let next = index.next.as_ref().unwrap();
+    assert_eq!(chained_first_page_id, next.first_managed_page_id);
+    assert_eq!(Some(index.page_id), next.parent_page_id());
+    assert_eq!(next.page_id, next_index_page_id);
+    assert!(page > next_index_page_id);
+
+    assert_eq!(Some(true), index.free(page, &store, &mut |_| true).unwrap());
+    assert_eq!(Some(false), index.next.as_ref().unwrap().dirty_bitmaps[&1].is_allocated(page));
+}
+
+#[test]
+fn aggregates_follow_loaded_chain() {
+    let store = temporary_store();
+    let mut index = chained_index();
+    let next_page_id = index.next_index_page_id().unwrap();
+    index.next.as_mut().unwrap().allocate(&store, &mut |_| true).unwrap().unwrap();
+
+    assert_eq!(2 * BITMAP_PAGE_COUNT as u64, index.total_managed_pages());
+    assert_eq!(4 * BITMAP_PAGE_COUNT as u64, index.chain_managed_pages(&store).unwrap());
+    assert_eq!(index.total_free_pages() + index.next.as_ref().unwrap().total_free_pages(), index.chain_free_pages(&store).unwrap());
+    assert_eq!(index.chain_free_pages(&store).unwrap() + 7, index.chain_managed_pages(&store).unwrap());
+
+    let metadata_pages = index.metadata_pages(&store).unwrap();
+    assert_eq!(6, metadata_pages.len());
+    assert!(metadata_pages.contains(&(next_page_id, PageType::Index, index.managed_end().unwrap())));
+
+    let fingerprint = index.state_fingerprint(&store).unwrap();
+    index.next.as_mut().unwrap().allocate(&store, &mut |_| true).unwrap().unwrap();
+    assert_ne!(fingerprint, index.state_fingerprint(&store).unwrap());
+
+    let next = index.next.as_mut().unwrap();
+    let free_page_offset = next.free_page_offset();
+    put_u32(&mut next.buffer, INDEX_HEADER_SIZE + free_page_offset, 7);
+    assert_eq!(vec![(next_page_id, 0)], index.verify(&store).unwrap());
+}
+
+#[test]
+fn aggregates_and_state_follow_persisted_chain() {
+    let max_size = (chained_index().managed_end().unwrap() as usize + 2 * BITMAP_PAGE_COUNT as usize) * 4096;
+    let mut store = PageStore::new(tempfile().unwrap(), max_size).unwrap();
+    let mut index = chained_index();
+    index.next.as_mut().unwrap().allocate(&store, &mut |_| true).unwrap().unwrap();
+    index.persist(&mut store).unwrap();
+    let free_pages = index.chain_free_pages(&store).unwrap();
+    let fingerprint = index.state_fingerprint(&store).unwrap();
+
+    let memory = store.read_page(index.page_id as usize).unwrap();
+    let reloaded = IndexPage::view(&memory).unwrap();
+    assert!(reloaded.next.is_none());
+    assert_eq!(free_pages, reloaded.chain_free_pages(&store).unwrap());
+    assert_eq!(fingerprint, reloaded.state_fingerprint(&store).unwrap());
+    assert_eq!(index.metadata_pages(&store).unwrap(), reloaded.metadata_pages(&store).unwrap());
+    assert!(reloaded.verify(&store).unwrap().is_empty());
+
+    let blob = AllocatorStateBlob::from_bytes(reloaded.export_state(&store).unwrap().bytes().to_vec()).unwrap();
+    let mut replica = PageStore::new(tempfile().unwrap(), max_size).unwrap();
+    let imported = IndexPage::import_state(&blob, &mut replica).unwrap();
+
+    assert_eq!(index.next_index_page_id(), imported.next_index_page_id());
+    assert_eq!(fingerprint, imported.state_fingerprint(&replica).unwrap());
+    assert_eq!(free_pages, imported.chain_free_pages(&replica).unwrap());
+}
+
+#[test]
+fn rejects_allocator_state_with_gap_in_chain() {
+    let store = temporary_store();
+    let mut index = chained_index();
+    index.next.as_mut().unwrap().first_managed_page_id += BITMAP_PAGE_COUNT as u64;
+
+    let blob = AllocatorStateBlob::from_bytes(index.export_state(&store).unwrap().bytes().to_vec()).unwrap();
+
+    assert_eq!(ErrorKind::InvalidData, IndexPage::import_state(&blob, &mut temporary_store()).err().unwrap().kind());
+}
+
+#[test]
+fn next_index_page_id_round_trips() {
+    let mut store = temporary_store();
+
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    assert_eq!(None, index.next_index_page_id());
+    index.next_index_page_id = 1000;
+    let index = index.persist_and_reload(&mut store, |_| true).unwrap().unwrap();
+
+    assert_eq!(Some(1000), index.next_index_page_id());
+    assert!(index.next.is_none());
+}
+
+#[test]
+fn free_beyond_unchained_index_is_rejected() {
+    let store = temporary_store();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let beyond = 2 + INDEX_BITMAP_COUNT as u64 * BITMAP_PAGE_COUNT as u64;
+
+    assert_eq!(None, index.free(beyond, &store, &mut |_| true).unwrap());
+    assert_eq!(None, index.next_index_page_id());
+}
+
+#[test]
+fn free_below_first_managed_page_is_rejected() {
+    let mut store = temporary_store();
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+    assert_eq!(None, index.free(0, &store, &mut |_| true).unwrap());
+    assert_eq!(None, index.free(1, &store, &mut |_| true).unwrap());
+
+    index.persist(&mut store).unwrap();
+    let memory = store.read_page(index.page_id as usize).unwrap();
+    let mut loaded = IndexPage::load(&memory, &store, |_| true).unwrap().unwrap();
+    drop(memory);
+    loaded.dirty_bitmaps.clear();
+    assert_eq!(None, loaded.free_unloaded(1, &store, &mut |_| true).unwrap());
+}
+
 fn temporary_store() -> PageStore {
     let file = tempfile().unwrap();
-    let store = PageStore::new(file, 3 * 4080 * 8 * 4096 + 2).unwrap();
-    store
+    PageStore::new(file, 5 * BITMAP_PAGE_COUNT as usize * 4096 + 2).unwrap()
+}
+
+fn slot_free_page_counts(index: &IndexPage) -> Vec<u32> {
+    (0..index.current_bitmap_count)
+        .map(|idx| get_u32(&index.buffer, INDEX_HEADER_SIZE + index.free_page_offset() + idx as usize * 4))
+        .collect()
+}
+
+
+// a small index with a second one chained right after its managed range, all bitmaps loaded
+fn chained_index() -> Pin<Box<IndexPage>> {
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    let mut next = IndexPage::grow(BitmapPage::new(index.managed_end().unwrap()).unwrap()).unwrap();
+    next.parent_page_id = index.page_id;
+    index.next_index_page_id = next.page_id;
+    index.next = Some(next);
+    index
+}
+
+// every bitmap slot of the index is taken and full
+fn saturated_index() -> Pin<Box<IndexPage>> {
+    let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+    index.dirty_bitmaps.clear();
+    index.current_bitmap_count = INDEX_BITMAP_COUNT;
+    for idx in 0..INDEX_BITMAP_COUNT {
+        index.update_bitmap_data(idx, 2 + idx as u64 * BITMAP_PAGE_COUNT as u64, 0);
+    }
+    index
 }
@@ -1,18 +1,95 @@
+use std::convert::TryFrom;
 use std::error;
 use std::io::{Error, ErrorKind, Result};
 
 pub mod bitmap;
+pub mod freelist;
 pub mod index;
+pub mod slotted;
 pub mod store;
 
-const PAGE_SIZE: usize = 4096;
+pub(crate) const PAGE_SIZE: usize = 4096;
+// bitmap and index pages are laid out for the page size of their store, down to this size
+pub(crate) const MIN_PAGE_SIZE: usize = 512;
+const PAGE_TRAILER_SIZE: usize = 4;
+// the last usable page id, 0xFFFF_FFFF marks a missing page in headers
+pub const MAX_PAGE_ID: u32 = 0xFFFF_FFFE;
+// the last usable page id of a database created with u64 page ids, all bits set marks a missing page
+pub const MAX_WIDE_PAGE_ID: u64 = 0xFFFF_FFFF_FFFF_FFFE;
 
-enum PageType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageType {
     Bitmap = 1,
     Index = 2,
+    Slotted = 3,
+    FreeList = 4,
+    WideBitmap = 5,
+    WideIndex = 6,
 }
 
-fn invalid_input<T, E>(message: E) -> Result<T>
+// how bitmap and index pages store page ids. u32 is the default, a new database can opt into u64 to address
+// more than MAX_PAGE_ID pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageIdWidth {
+    U32,
+    U64,
+}
+
+impl PageIdWidth {
+    pub const fn bytes(self) -> usize {
+        match self {
+            PageIdWidth::U32 => 4,
+            PageIdWidth::U64 => 8,
+        }
+    }
+
+    pub fn max_page_id(self) -> u64 {
+        match self {
+            PageIdWidth::U32 => MAX_PAGE_ID as u64,
+            PageIdWidth::U64 => MAX_WIDE_PAGE_ID,
+        }
+    }
+
+    pub(crate) fn bitmap_type(self) -> PageType {
+        match self {
+            PageIdWidth::U32 => PageType::Bitmap,
+            PageIdWidth::U64 => PageType::WideBitmap,
+        }
+    }
+
+    pub(crate) fn index_type(self) -> PageType {
+        match self {
+            PageIdWidth::U32 => PageType::Index,
+            PageIdWidth::U64 => PageType::WideIndex,
+        }
+    }
+
+    // the width a stored bitmap or index page was written with, taken from its page type
+    pub(crate) fn of(header: &[u8]) -> PageIdWidth {
+        match get_u32(header, 4) {
+            5 | 6 => PageIdWidth::U64,
+            _ => PageIdWidth::U32,
+        }
+    }
+}
+
+impl TryFrom<u32> for PageType {
+    type Error = Error;
+
+    fn try_from(value: u32) -> Result<PageType> {
+        match value {
+            1 => Ok(PageType::Bitmap),
+            2 => Ok(PageType::Index),
+            3 => Ok(PageType::Slotted),
+            4 => Ok(PageType::FreeList),
+            5 => Ok(PageType::WideBitmap),
+            6 => Ok(PageType::WideIndex),
+            _ => invalid_data(format!("unknown page type {}", value)),
+        }
+    }
+}
+
+pub(crate) fn invalid_input<T, E>(message: E) -> Result<T>
     where E: Into<Box<dyn error::Error + Send + Sync>>
 {
     Err(Error::new(
@@ -21,3 +98,138 @@ fn invalid_input<T, E>(message: E) -> Result<T>
     ))
 }
 
+pub(crate) fn invalid_data<T, E>(message: E) -> Result<T>
+    where E: Into<Box<dyn error::Error + Send + Sync>>
+{
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        message,
+    ))
+}
+
+fn permission_denied<T, E>(message: E) -> Result<T>
+    where E: Into<Box<dyn error::Error + Send + Sync>>
+{
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        message,
+    ))
+}
+
+pub(crate) fn check_page_size(page_size: usize) -> Result<()> {
+    if !page_size.is_power_of_two() || page_size < MIN_PAGE_SIZE {
+        return invalid_input(
+            format!("invalid page size, {} is not a power of two of at least {} bytes", page_size, MIN_PAGE_SIZE)
+        );
+    }
+    Ok(())
+}
+
+fn expect_sized_page(content: &[u8]) -> Result<&[u8]> {
+    if !content.len().is_power_of_two() || content.len() < MIN_PAGE_SIZE {
+        return invalid_data(
+            format!("invalid page, {} bytes is not a power of two of at least {}", content.len(), MIN_PAGE_SIZE)
+        );
+    }
+    Ok(content)
+}
+
+pub(crate) fn put_u16(buffer: &mut [u8], idx: usize, value: u16) {
+    buffer[idx..idx + 2].clone_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn put_u32(buffer: &mut [u8], idx: usize, value: u32) {
+    buffer[idx..idx + 4].clone_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut [u8], idx: usize, value: u64) {
+    buffer[idx..idx + 8].clone_from_slice(&value.to_le_bytes());
+}
+
+fn get_u32(buffer: &[u8], idx: usize) -> u32 {
+    let mut bytes: [u8; 4] = [0; 4];
+    bytes.copy_from_slice(&buffer[idx..idx + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn get_u64(buffer: &[u8], idx: usize) -> u64 {
+    let mut bytes: [u8; 8] = [0; 8];
+    bytes.copy_from_slice(&buffer[idx..idx + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// a missing page is u64::MAX in memory and all bits set on disk, whatever the width
+pub(crate) fn put_page_id(buffer: &mut [u8], idx: usize, width: PageIdWidth, page_id: u64) {
+    match width {
+        PageIdWidth::U32 => put_u32(buffer, idx, page_id as u32),
+        PageIdWidth::U64 => put_u64(buffer, idx, page_id),
+    }
+}
+
+pub(crate) fn get_page_id(buffer: &[u8], idx: usize, width: PageIdWidth) -> u64 {
+    match width {
+        PageIdWidth::U32 => match get_u32(buffer, idx) {
+            u32::MAX => u64::MAX,
+            page_id => page_id as u64,
+        },
+        PageIdWidth::U64 => get_u64(buffer, idx),
+    }
+}
+
+// bitmap and index pages start with page id 0..4, type 4..8 and first managed page 8..12. with u64 page ids the
+// first managed page takes 8..16 and the upper half of the page id follows at 16..20
+pub(crate) const fn header_prefix_size(width: PageIdWidth) -> usize {
+    match width {
+        PageIdWidth::U32 => 12,
+        PageIdWidth::U64 => 20,
+    }
+}
+
+pub(crate) fn put_header_prefix(buffer: &mut [u8], page_type: PageType, width: PageIdWidth, page_id: u64, first_managed_page_id: u64) {
+    put_u32(buffer, 0, page_id as u32);
+    put_u32(buffer, 4, page_type as u32);
+    put_page_id(buffer, 8, width, first_managed_page_id);
+    if width == PageIdWidth::U64 {
+        put_u32(buffer, 16, (page_id >> 32) as u32);
+    }
+}
+
+// rewrites only the page id, the width is taken from the page type already in the header
+pub(crate) fn put_header_page_id(header: &mut [u8], page_id: u64) {
+    put_u32(header, 0, page_id as u32);
+    if PageIdWidth::of(header) == PageIdWidth::U64 {
+        put_u32(header, 16, (page_id >> 32) as u32);
+    }
+}
+
+pub(crate) fn header_page_id(header: &[u8]) -> u64 {
+    match PageIdWidth::of(header) {
+        PageIdWidth::U32 => get_u32(header, 0) as u64,
+        PageIdWidth::U64 => (get_u32(header, 16) as u64) << 32 | get_u32(header, 0) as u64,
+    }
+}
+
+pub(crate) fn header_first_managed_page_id(header: &[u8]) -> u64 {
+    get_page_id(header, 8, PageIdWidth::of(header))
+}
+
+fn put_sentinel(buffer: &mut [u8], idx: usize, generation: u32) {
+    let bytes = generation.to_le_bytes();
+    let trailer = buffer.len() - PAGE_TRAILER_SIZE;
+    buffer[idx..idx + 4].clone_from_slice(&bytes);
+    buffer[trailer..].clone_from_slice(&bytes);
+}
+
+fn check_sentinel(buffer: &[u8], idx: usize) -> Result<u32> {
+    let mut head: [u8; 4] = [0; 4];
+    head.copy_from_slice(&buffer[idx..idx + 4]);
+    let mut tail: [u8; 4] = [0; 4];
+    tail.copy_from_slice(&buffer[buffer.len() - PAGE_TRAILER_SIZE..]);
+
+    if head != tail {
+        return invalid_data(
+            format!("torn page, generation {} does not match trailer {}", u32::from_le_bytes(head), u32::from_le_bytes(tail))
+        );
+    }
+    Ok(u32::from_le_bytes(head))
+}
@@ -0,0 +1,373 @@
+use std::cmp::Reverse;
+use std::io::Result;
+use std::pin::Pin;
+use crate::io::{PAGE_SIZE, PAGE_TRAILER_SIZE, PageType, check_page_size, expect_sized_page, invalid_data, invalid_input, put_sentinel, check_sentinel, put_u16, put_u32};
+use crate::io::store::{MemoryPage, PageStore};
+
+// page id 0..4, type 4..8, slot count 8..10, free space end 10..12, generation 12..16
+const SLOTTED_HEADER_SIZE: usize = 16;
+const SLOTTED_GENERATION_OFFSET: usize = 12;
+const _: () = assert!(SLOTTED_GENERATION_OFFSET + 4 <= SLOTTED_HEADER_SIZE);
+// record offsets are u16, so the record area has to end within the first 64 KiB
+const MAX_SLOTTED_PAGE_SIZE: usize = 65536;
+// record offset u16 + record length u16, an offset of 0 marks a removed record
+const SLOT_SIZE: usize = 4;
+
+pub struct SlottedPage {
+    page_id: u32,
+    slot_count: u16,
+    free_space_end: u16,
+    generation: u32,
+    buffer: Vec<u8>,
+}
+
+impl SlottedPage {
+    pub fn new(page_id: u32) -> Pin<Box<SlottedPage>> {
+        SlottedPage::sized(page_id, PAGE_SIZE)
+    }
+
+    pub fn with_page_size(page_id: u32, page_size: usize) -> Result<Pin<Box<SlottedPage>>> {
+        check_slotted_page_size(page_size)?;
+        Ok(SlottedPage::sized(page_id, page_size))
+    }
+
+    fn sized(page_id: u32, page_size: usize) -> Pin<Box<SlottedPage>> {
+        Box::pin(SlottedPage {
+            page_id,
+            slot_count: 0,
+            free_space_end: slotted_end(page_size) as u16,
+            generation: 0,
+            buffer: vec![0; page_size],
+        })
+    }
+
+    pub fn load(page: &MemoryPage) -> Result<Pin<Box<SlottedPage>>> {
+        let buffer = expect_sized_page(page.content())?.to_vec();
+        if buffer.len() > MAX_SLOTTED_PAGE_SIZE {
+            return invalid_data(
+                format!("invalid page, slotted pages address at most {} bytes but got {}", MAX_SLOTTED_PAGE_SIZE, buffer.len())
+            );
+        }
+        let record_end = slotted_end(buffer.len());
+        let generation = check_sentinel(&buffer, SLOTTED_GENERATION_OFFSET)?;
+        if page.page_type() != PageType::Slotted as u32 {
+            return invalid_data(
+                format!("unexpected page type {} (expected {})", page.page_type(), PageType::Slotted as u32)
+            );
+        }
+        let slot_count = page.get_u16(8);
+        let free_space_end = page.get_u16(10);
+        if SLOTTED_HEADER_SIZE + slot_count as usize * SLOT_SIZE > free_space_end as usize
+            || free_space_end as usize > record_end {
+            return invalid_data(
+                format!("corrupt slotted page, {} slots do not fit below free space end {}", slot_count, free_space_end)
+            );
+        }
+
+        let page = Box::pin(SlottedPage {
+            page_id: page.page_id() as u32,
+            slot_count,
+            free_space_end,
+            generation,
+            buffer,
+        });
+        // a live record outside the record area would make get return garbage and compact panic
+        for slot in 0..slot_count {
+            let (offset, length) = page.slot(slot);
+            let (start, end) = (offset as usize, offset as usize + length as usize);
+            if offset != 0 && (start < page.directory_end() || end > record_end) {
+                return invalid_data(
+                    format!("corrupt slotted page, slot {} points at {}..{} outside the record area", slot, start, end)
+                );
+            }
+        }
+        Ok(page)
+    }
+
+    pub fn page_id(&self) -> u32 {
+        self.page_id
+    }
+
+    pub fn slot_count(&self) -> u16 {
+        self.slot_count
+    }
+
+    pub fn free_space(&self) -> usize {
+        self.free_space_end as usize - self.directory_end()
+    }
+
+    pub fn insert(&mut self, bytes: &[u8]) -> Option<u16> {
+        let reusable = (0..self.slot_count).find(|slot| self.slot(*slot).0 == 0);
+        let directory_growth = if reusable.is_some() { 0 } else { SLOT_SIZE };
+        if bytes.len() + directory_growth > self.free_space() {
+            return None;
+        }
+
+        let slot = reusable.unwrap_or(self.slot_count);
+        if reusable.is_none() {
+            self.slot_count += 1;
+        }
+        let offset = self.free_space_end as usize - bytes.len();
+        self.buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.free_space_end = offset as u16;
+        self.set_slot(slot, offset as u16, bytes.len() as u16);
+        Some(slot)
+    }
+
+    pub fn get(&self, slot: u16) -> Option<&[u8]> {
+        if slot >= self.slot_count {
+            return None;
+        }
+        let (offset, length) = self.slot(slot);
+        if offset == 0 {
+            return None;
+        }
+        let start = offset as usize;
+        self.buffer.get(start..start + length as usize)
+    }
+
+    pub fn remove(&mut self, slot: u16) -> bool {
+        if self.get(slot).is_none() {
+            return false;
+        }
+        self.set_slot(slot, 0, 0);
+        true
+    }
+
+    pub fn compact(&mut self) {
+        let mut live = (0..self.slot_count)
+            .map(|slot| (slot, self.slot(slot)))
+            .filter(|(_, (offset, _))| *offset != 0)
+            .collect::<Vec<_>>();
+        // moving records nearest to the end first never overwrites one that has not moved yet
+        live.sort_unstable_by_key(|(_, (offset, _))| Reverse(*offset));
+
+        let mut end = slotted_end(self.buffer.len());
+        for (slot, (offset, length)) in live {
+            let start = end - length as usize;
+            self.buffer.copy_within(offset as usize..offset as usize + length as usize, start);
+            self.set_slot(slot, start as u16, length);
+            end = start;
+        }
+        self.free_space_end = end as u16;
+
+        while self.slot_count > 0 && self.slot(self.slot_count - 1).0 == 0 {
+            self.slot_count -= 1;
+        }
+    }
+
+    pub fn persist(&mut self, store: &mut PageStore) -> Result<()> {
+        self.generation = self.generation.wrapping_add(1);
+        self.update_header();
+
+        Ok(store.write_page(self.page_id as usize, &self.buffer)?)
+    }
+
+    fn directory_end(&self) -> usize {
+        SLOTTED_HEADER_SIZE + self.slot_count as usize * SLOT_SIZE
+    }
+
+    fn slot(&self, slot: u16) -> (u16, u16) {
+        let idx = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        (
+            u16::from_le_bytes([self.buffer[idx], self.buffer[idx + 1]]),
+            u16::from_le_bytes([self.buffer[idx + 2], self.buffer[idx + 3]]),
+        )
+    }
+
+    fn set_slot(&mut self, slot: u16, offset: u16, length: u16) {
+        let idx = SLOTTED_HEADER_SIZE + slot as usize * SLOT_SIZE;
+        put_u16(&mut self.buffer, idx, offset);
+        put_u16(&mut self.buffer, idx + 2, length);
+    }
+
+    fn update_header(&mut self) {
+        put_u32(&mut self.buffer, 0, self.page_id);
+        put_u32(&mut self.buffer, 4, PageType::Slotted as u32);
+        put_u16(&mut self.buffer, 8, self.slot_count);
+        put_u16(&mut self.buffer, 10, self.free_space_end);
+        put_sentinel(&mut self.buffer, SLOTTED_GENERATION_OFFSET, self.generation);
+    }
+}
+
+fn slotted_end(page_size: usize) -> usize {
+    page_size - PAGE_TRAILER_SIZE
+}
+
+fn check_slotted_page_size(page_size: usize) -> Result<()> {
+    check_page_size(page_size)?;
+    if page_size > MAX_SLOTTED_PAGE_SIZE {
+        return invalid_input(
+            format!("invalid page size, slotted pages address at most {} bytes but got {}", MAX_SLOTTED_PAGE_SIZE, page_size)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::bitmap::BitmapPage;
+    use crate::io::slotted::SlottedPage;
+    use crate::io::store::PageStore;
+    use std::io::ErrorKind;
+    use tempfile::tempfile;
+
+    const TESTDB_MAX_SIZE: usize = 163840;
+
+    #[test]
+    fn inserts_and_reads_back_records() {
+        let mut page = SlottedPage::new(3);
+
+        let first = page.insert(b"hello").unwrap();
+        let second = page.insert(b"").unwrap();
+        let third = page.insert(b"world!").unwrap();
+
+        assert_eq!((0, 1, 2), (first, second, third));
+        assert_eq!(Some(&b"hello"[..]), page.get(first));
+        assert_eq!(Some(&b""[..]), page.get(second));
+        assert_eq!(Some(&b"world!"[..]), page.get(third));
+        assert_eq!(None, page.get(3));
+    }
+
+    #[test]
+    fn removed_slot_is_reused() {
+        let mut page = SlottedPage::new(3);
+        page.insert(b"one").unwrap();
+        let two = page.insert(b"two").unwrap();
+
+        assert!(page.remove(two));
+        assert!(!page.remove(two));
+        assert_eq!(None, page.get(two));
+
+        assert_eq!(Some(two), page.insert(b"three"));
+        assert_eq!(2, page.slot_count());
+        assert_eq!(Some(&b"three"[..]), page.get(two));
+    }
+
+    #[test]
+    fn rejects_record_that_does_not_fit() {
+        let mut page = SlottedPage::new(3);
+        let free_space = page.free_space();
+
+        assert_eq!(None, page.insert(&vec![1; free_space - 3]));
+        assert_eq!(Some(0), page.insert(&vec![1; free_space - 4]));
+        assert_eq!(0, page.free_space());
+        assert_eq!(None, page.insert(b""));
+    }
+
+    #[test]
+    fn compaction_reclaims_removed_records() {
+        let mut page = SlottedPage::new(3);
+        let records = (0..8u8).map(|i| vec![i; 10 + i as usize]).collect::<Vec<_>>();
+        let slots = records.iter().map(|record| page.insert(record).unwrap()).collect::<Vec<_>>();
+        for slot in slots.iter().step_by(2) {
+            assert!(page.remove(*slot));
+        }
+        let free_space = page.free_space();
+
+        page.compact();
+
+        assert_eq!(free_space + (10 + 12 + 14 + 16), page.free_space());
+        for (i, slot) in slots.iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(None, page.get(*slot));
+            } else {
+                assert_eq!(Some(&records[i][..]), page.get(*slot));
+            }
+        }
+        let big = vec![0xAB; page.free_space()];
+        assert_eq!(Some(0), page.insert(&big));
+        assert_eq!(Some(&big[..]), page.get(0));
+        assert_eq!(Some(&records[7][..]), page.get(7));
+    }
+
+    #[test]
+    fn compaction_drops_trailing_removed_slots() {
+        let mut page = SlottedPage::new(3);
+        page.insert(b"keep").unwrap();
+        let removed = page.insert(b"drop").unwrap();
+        page.remove(removed);
+
+        page.compact();
+
+        assert_eq!(1, page.slot_count());
+        assert_eq!(Some(&b"keep"[..]), page.get(0));
+    }
+
+    #[test]
+    fn persists_and_loads() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        let mut page = SlottedPage::new(3);
+        page.insert(b"hello").unwrap();
+        let removed = page.insert(b"gone").unwrap();
+        page.insert(b"world").unwrap();
+        page.remove(removed);
+        page.persist(&mut store).unwrap();
+
+        let loaded = SlottedPage::load(&store.read_page(3).unwrap()).unwrap();
+
+        assert_eq!(3, loaded.page_id());
+        assert_eq!(3, loaded.slot_count());
+        assert_eq!(page.free_space(), loaded.free_space());
+        assert_eq!(Some(&b"hello"[..]), loaded.get(0));
+        assert_eq!(None, loaded.get(removed));
+        assert_eq!(Some(&b"world"[..]), loaded.get(2));
+    }
+
+    #[test]
+    fn persists_and_loads_at_store_page_size() {
+        let page_size = 1024;
+        let file = tempfile().unwrap();
+        let mut store = PageStore::with_page_size(file, 8 * page_size, page_size).unwrap();
+
+        let mut page = SlottedPage::with_page_size(3, page_size).unwrap();
+        let free_space = page.free_space();
+        page.insert(&vec![7; free_space - 4]).unwrap();
+        page.persist(&mut store).unwrap();
+
+        let loaded = SlottedPage::load(&store.read_page(3).unwrap()).unwrap();
+
+        assert_eq!(page_size - 4 - 16, free_space);
+        assert_eq!(0, loaded.free_space());
+        assert_eq!(Some(&vec![7; free_space - 4][..]), loaded.get(0));
+    }
+
+    #[test]
+    fn rejects_page_size_beyond_record_offsets() {
+        assert!(SlottedPage::with_page_size(3, 65536).is_ok());
+        assert_eq!(ErrorKind::InvalidInput, SlottedPage::with_page_size(3, 2 * 65536).err().unwrap().kind());
+        assert_eq!(ErrorKind::InvalidInput, SlottedPage::with_page_size(3, 256).err().unwrap().kind());
+    }
+
+    #[test]
+    fn cannot_load_page_with_slot_outside_record_area() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        let mut page = SlottedPage::new(3);
+        page.insert(b"hello").unwrap();
+        page.insert(b"world").unwrap();
+        page.persist(&mut store).unwrap();
+
+        // slot 1 now claims 100 bytes starting inside the slot directory
+        store.write_page_range(3, 20, &[18, 0, 100, 0]).unwrap();
+
+        match SlottedPage::load(&store.read_page(3).unwrap()) {
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("should not have loaded a page with a corrupt slot")
+        }
+    }
+
+    #[test]
+    fn cannot_load_other_page_type() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+
+        match SlottedPage::load(&store.read_page(2).unwrap()) {
+            Err(e) => assert_eq!(ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("should not have loaded a bitmap page as slotted page")
+        }
+    }
+}
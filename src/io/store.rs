@@ -1,183 +1,1558 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Display, Write as FmtWrite};
 use std::fs::File;
-use std::io::{Seek, Write, SeekFrom};
-use std::io::{Result};
-use memmap::{Mmap, MmapOptions};
+use std::ops::{Deref, DerefMut, Range};
+use std::pin::Pin;
+use std::io::{self, Error, ErrorKind, Result};
+use std::time::Duration;
+use memmap::{Mmap, MmapMut, MmapOptions};
 use std::sync::Arc;
-use crate::io::{PAGE_SIZE, invalid_input};
+use crate::io::{MIN_PAGE_SIZE, PAGE_SIZE, PageType, check_page_size, header_first_managed_page_id, header_page_id, invalid_data, put_header_page_id, invalid_input, permission_denied, put_u64};
+use crate::io::bitmap::BitmapPage;
+use crate::io::index::IndexPage;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub pages_checked: usize,
+    pub problems: Vec<(u64, String)>,
+}
+
+impl VerifyReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    pub current_size: usize,
+    pub max_size: usize,
+    pub page_count: usize,
+    pub available_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    Silent,
+    Warn,
+    Panic,
+}
+
+#[derive(Debug)]
+pub enum PageStoreError {
+    PageBeyondMaxSize { max_size: usize },
+    PageNotYetAllocated { current_size: usize },
+    BufferSizeMismatch { expected: usize, got: usize },
+    WriteOverrunsPage,
+    PageUninitialized { id: usize },
+    Io(io::Error),
+}
+
+impl PageStoreError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PageStoreError::Io(e) => e.kind(),
+            PageStoreError::PageUninitialized { .. } => ErrorKind::InvalidData,
+            _ => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl Display for PageStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageStoreError::PageBeyondMaxSize { max_size } =>
+                write!(f, "invalid page, the specified page is beyond maximum file size (max size = {})", max_size),
+            PageStoreError::PageNotYetAllocated { current_size } =>
+                write!(f, "invalid page, the specified page does not yet exist(current size = {})", current_size),
+            PageStoreError::BufferSizeMismatch { expected, got } =>
+                write!(f, "invalid size, buf needs to hold exactly {} bytes but holds {}", expected, got),
+            PageStoreError::WriteOverrunsPage =>
+                write!(f, "invalid (offset,size), write would overrun page"),
+            PageStoreError::PageUninitialized { id } =>
+                write!(f, "uninitialized page, page {} has been allocated but never written", id),
+            PageStoreError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for PageStoreError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PageStoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PageStoreError {
+    fn from(e: io::Error) -> PageStoreError {
+        PageStoreError::Io(e)
+    }
+}
+
+impl From<PageStoreError> for io::Error {
+    fn from(e: PageStoreError) -> io::Error {
+        match e {
+            PageStoreError::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+pub type StoreResult<T> = std::result::Result<T, PageStoreError>;
 
 pub struct PageStore {
     file: File,
     mmap: Arc<Mmap>,
+    mmap_mut: Option<MmapMut>,
     pub(crate) max_size: usize,
     pub(crate) current_size: usize,
+    page_size: usize,
+    dirty_since_flush: bool,
+    sync_count: usize,
+    unwritten_pages: HashSet<usize>,
+    strict_reads: bool,
+    reserved_pages: usize,
+    drop_policy: DropPolicy,
+    sequence_page: Option<usize>,
+    prefetch_hook: Option<PrefetchHook>,
 }
 
+type PrefetchHook = Box<dyn Fn(Range<usize>) + Send + Sync>;
+
 impl PageStore {
     pub fn new(file: File, max_size: usize) -> Result<PageStore> {
+        PageStore::with_page_size(file, max_size, PAGE_SIZE)
+    }
+
+    pub fn with_page_size(file: File, max_size: usize, page_size: usize) -> Result<PageStore> {
+        check_page_size(page_size)?;
         let current_size = file.metadata()?.len() as usize;
+        // pages past the mapping could be addressed but not read
+        if current_size > max_size {
+            return invalid_input(
+                format!("max size too small, the file has {} bytes but only {} can be mapped", current_size, max_size)
+            );
+        }
         let mem = unsafe {
             MmapOptions::new().len(max_size).map(&file)?
         };
+        let mmap_mut = unsafe {
+            MmapOptions::new().len(max_size).map_mut(&file)?
+        };
+        Ok(PageStore::from_mappings(file, mem, Some(mmap_mut), max_size, current_size, page_size))
+    }
+
+    // the store cannot tell the page size of an existing file, Database::open_read_only reads it from the header
+    pub fn open_read_only(file: File, page_size: usize) -> Result<PageStore> {
+        check_page_size(page_size)?;
+        let current_size = file.metadata()?.len() as usize;
+        let mem = unsafe {
+            MmapOptions::new().len(current_size).map(&file)?
+        };
+        Ok(PageStore::from_mappings(file, mem, None, current_size, current_size, page_size))
+    }
+
+    fn from_mappings(file: File, mem: Mmap, mmap_mut: Option<MmapMut>, max_size: usize, current_size: usize, page_size: usize) -> PageStore {
         let mmap = Arc::new(mem);
-        Ok(PageStore { file, mmap, max_size, current_size })
+        PageStore {
+            file,
+            mmap,
+            mmap_mut,
+            max_size,
+            current_size,
+            page_size,
+            dirty_since_flush: false,
+            sync_count: 0,
+            unwritten_pages: HashSet::new(),
+            strict_reads: false,
+            reserved_pages: 0,
+            drop_policy: DropPolicy::Silent,
+            sequence_page: None,
+            prefetch_hook: None,
+        }
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            current_size: self.current_size,
+            max_size: self.max_size,
+            // a trailing partial page cannot be read, so it is not counted
+            page_count: self.current_size / self.page_size,
+            available_bytes: self.max_size.saturating_sub(self.current_size),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.mmap_mut.is_none()
+    }
+
+    // unwritten pages are only tracked while strict reads are on, pages grown before that are not checked
+    pub fn set_strict_reads(&mut self, strict_reads: bool) {
+        self.strict_reads = strict_reads;
+        if !strict_reads {
+            self.unwritten_pages.clear();
+        }
+    }
+
+    pub fn set_huge_pages(&mut self, huge_pages: bool) {
+        advise_huge_pages(&self.mmap, huge_pages);
+    }
+
+    // only a hint to the kernel, silently ignored where madvise is unavailable
+    pub fn advise(&self, advice: Advice) {
+        advise_mapping(&self.mmap, advice);
+    }
+
+    pub fn set_drop_policy(&mut self, drop_policy: DropPolicy) {
+        self.drop_policy = drop_policy;
+    }
+
+    pub fn set_reserved_pages(&mut self, reserved_pages: usize) {
+        self.reserved_pages = reserved_pages;
+    }
+
+    pub fn reserved_pages(&self) -> usize {
+        self.reserved_pages
+    }
+
+    pub fn set_sequence_page(&mut self, id: usize) -> Result<()> {
+        if id >= self.reserved_pages {
+            return invalid_input(
+                format!("invalid sequence page, page {} is not a reserved page (reserved pages = {})", id, self.reserved_pages)
+            );
+        }
+        self.sequence_page = Some(id);
+        Ok(())
+    }
+
+    pub fn next_sequence(&mut self) -> Result<u64> {
+        self.next_sequence_block(1)
+    }
+
+    pub fn next_sequence_block(&mut self, count: u64) -> Result<u64> {
+        let id = match self.sequence_page {
+            Some(id) => id,
+            None => return invalid_input("no sequence page, configure one with set_sequence_page".to_string()),
+        };
+
+        let mut buffer = vec![0u8; self.page_size];
+        if self.page_offset(id)? + self.page_size <= self.current_size {
+            buffer.copy_from_slice(self.read_page(id)?.content());
+        }
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&buffer[..8]);
+        let first = u64::from_le_bytes(bytes);
+        let next = match first.checked_add(count) {
+            Some(next) => next,
+            None => return invalid_data(format!("sequence exhausted, cannot reserve {} ids after {}", count, first)),
+        };
+
+        put_u64(&mut buffer, 0, next);
+        self.write_metadata_page(id, &buffer)?;
+        self.flush()?;
+        Ok(first)
     }
 
     pub fn flush(&mut self) -> Result<()> {
-        self.file.flush()?;
-        self.file.sync_data()
+        if !self.dirty_since_flush {
+            return Ok(());
+        }
+        sync_mapping(&self.file, self.mmap_mut.as_ref())?;
+        self.sync_count += 1;
+        self.dirty_since_flush = false;
+        Ok(())
+    }
+
+    // number of successful fsyncs issued by flush and flush_with_retry since the store was opened
+    pub fn sync_count(&self) -> usize {
+        self.sync_count
+    }
+
+    // hands the writes to the os page cache without an fsync, so they survive a process crash but not
+    // a power loss. the store stays dirty until the next flush, which remains the only durable commit
+    pub fn flush_async(&self) -> Result<()> {
+        if !self.dirty_since_flush {
+            return Ok(());
+        }
+        self.mmap_mut.as_ref().map_or(Ok(()), |mmap_mut| mmap_mut.flush_async())
+    }
+
+    pub fn flush_with_retry(&mut self, max_attempts: usize, backoff: Duration) -> Result<()> {
+        if !self.dirty_since_flush {
+            return Ok(());
+        }
+        let (file, mmap_mut) = (&self.file, self.mmap_mut.as_ref());
+        retry_with_backoff(max_attempts, backoff, || sync_mapping(file, mmap_mut))?;
+        self.sync_count += 1;
+        self.dirty_since_flush = false;
+        Ok(())
+    }
+
+    pub fn read_page(&self, id: usize) -> StoreResult<MemoryPage> {
+        let (start, end) = self.existing_page(id)?;
+        Ok(MemoryPage { start, end, mmap: self.mmap.clone() })
     }
 
-    pub fn read_page(&self, id: usize) -> Result<MemoryPage> {
-        let offset = id * PAGE_SIZE;
-        let end = offset + PAGE_SIZE;
+    pub fn iter_pages_of_type(&self, ty: PageType) -> impl Iterator<Item = MemoryPage> + '_ {
+        (0..self.current_size / self.page_size)
+            .filter_map(move |id| self.read_page(id).ok())
+            .filter(move |page| page.typed() == Some(ty))
+    }
+
+    pub fn prefetch(&self, start_id: usize, count: usize) {
+        let start = match start_id.checked_mul(self.page_size) {
+            Some(start) if start < self.current_size => start,
+            _ => return,
+        };
+        let end = start_id.saturating_add(count).saturating_mul(self.page_size).min(self.current_size);
+        if end <= start {
+            return;
+        }
+        advise_will_need(&self.mmap, start, end - start);
+        if let Some(hook) = &self.prefetch_hook {
+            hook(start_id..self.offset_to_page(end));
+        }
+    }
+
+    // called with the pages of every prefetch that reaches the kernel, for tracing read-ahead
+    pub fn set_prefetch_hook(&mut self, hook: impl Fn(Range<usize>) + Send + Sync + 'static) {
+        self.prefetch_hook = Some(Box::new(hook));
+    }
+
+    pub fn read_header(&self, id: usize) -> Result<PageHeaderSnapshot> {
+        let (start, _) = self.existing_page(id)?;
+        let mut bytes = [0; PAGE_HEADER_SNAPSHOT_SIZE];
+        bytes.copy_from_slice(&self.mmap[start..start + PAGE_HEADER_SNAPSHOT_SIZE]);
+        Ok(PageHeaderSnapshot { bytes })
+    }
+
+    pub fn page_mut(&mut self, id: usize) -> Result<PageMut<'_>> {
+        self.ensure_writable()?;
+        self.ensure_not_reserved(id)?;
+        let (start, end) = self.existing_page(id)?;
+        Ok(PageMut { store: self, id, start, end, dirty: false })
+    }
+
+    // like page_mut, but grows the store to the page instead of requiring it to exist
+    pub fn write_page_mut(&mut self, id: usize) -> Result<PageMut<'_>> {
+        self.ensure_not_reserved(id)?;
+        let start = self.page_offset(id)?;
+        self.ensure_page_exists_at(start)?;
+        let end = start + self.page_size;
+        Ok(PageMut { store: self, id, start, end, dirty: false })
+    }
+
+    pub fn dirty_bitmap(&mut self, id: usize) -> Result<DirtyBitmap<'_>> {
+        let memory = self.read_page(id)?;
+        let bitmap = BitmapPage::load_into(&memory, id as u64)?;
+        Ok(DirtyBitmap { store: self, bitmap, pending: true })
+    }
+
+    fn existing_page(&self, id: usize) -> StoreResult<(usize, usize)> {
+        let (offset, end) = self.allocated_page(id)?;
+        if self.strict_reads && self.unwritten_pages.contains(&id) {
+            return Err(PageStoreError::PageUninitialized { id });
+        }
+        Ok((offset, end))
+    }
+
+    fn allocated_page(&self, id: usize) -> StoreResult<(usize, usize)> {
+        let offset = self.page_offset(id)?;
+        let end = offset.saturating_add(self.page_size);
         if end > self.current_size {
+            return Err(if end > self.max_size {
+                PageStoreError::PageBeyondMaxSize { max_size: self.max_size }
+            } else {
+                PageStoreError::PageNotYetAllocated { current_size: self.current_size }
+            });
+        }
+        Ok((offset, end))
+    }
+
+    pub fn write_page(&mut self, id: usize, buf: &[u8]) -> StoreResult<()> {
+        self.ensure_not_reserved(id)?;
+        self.write_metadata_page(id, buf)
+    }
+
+    pub fn write_metadata_page(&mut self, id: usize, buf: &[u8]) -> StoreResult<()> {
+        if buf.len() != self.page_size {
+            return Err(PageStoreError::BufferSizeMismatch { expected: self.page_size, got: buf.len() });
+        }
+        self.write_buf_at(buf, self.page_offset(id)?)
+    }
+
+    pub fn write_page_range(&mut self, id: usize, offset: usize, buf: &[u8]) -> StoreResult<()> {
+        self.ensure_not_reserved(id)?;
+        if offset + buf.len() > self.page_size {
+            return Err(PageStoreError::WriteOverrunsPage);
+        }
+        self.write_buf_at(buf, self.page_offset(id)? + offset)
+    }
+
+    // copies within the mapping and stamps the destination page id into the copied header
+    pub fn copy_page(&mut self, src_id: usize, dst_id: usize) -> StoreResult<()> {
+        self.ensure_not_reserved(dst_id)?;
+        let (src_start, src_end) = self.existing_page(src_id)?;
+        let dst_start = self.page_offset(dst_id)?;
+        self.ensure_page_exists_at(dst_start)?;
+
+        let page_size = self.page_size;
+        let mapping = self.mapping_mut();
+        mapping.copy_within(src_start..src_end, dst_start);
+        put_header_page_id(&mut mapping[dst_start..dst_start + page_size], dst_id as u64);
+        self.dirty_since_flush = true;
+        self.unwritten_pages.remove(&dst_id);
+        Ok(())
+    }
+
+    // scrubs a freed page and flushes, so its old contents do not linger in the file. never grows the file
+    pub fn zero_page(&mut self, id: usize) -> StoreResult<()> {
+        self.ensure_writable()?;
+        self.ensure_not_reserved(id)?;
+        let (start, end) = self.allocated_page(id)?;
+
+        self.mapping_mut()[start..end].fill(0);
+        self.dirty_since_flush = true;
+        self.unwritten_pages.remove(&id);
+        Ok(self.flush()?)
+    }
+
+    pub fn write_pages(&mut self, start_id: usize, bufs: &[impl AsRef<[u8]>]) -> StoreResult<()> {
+        if bufs.is_empty() {
+            return Ok(());
+        }
+        self.ensure_not_reserved(start_id)?;
+        if let Some(buf) = bufs.iter().find(|buf| buf.as_ref().len() != self.page_size) {
+            return Err(PageStoreError::BufferSizeMismatch { expected: self.page_size, got: buf.as_ref().len() });
+        }
+        let start = self.page_offset(start_id)?;
+        let last = self.page_offset(start_id.saturating_add(bufs.len() - 1))?;
+        self.ensure_page_exists_at(last)?;
+
+        let page_size = self.page_size;
+        let mapping = self.mapping_mut();
+        for (idx, buf) in bufs.iter().enumerate() {
+            let offset = start + idx * page_size;
+            mapping[offset..offset + page_size].copy_from_slice(buf.as_ref());
+        }
+        self.dirty_since_flush = true;
+        for id in start_id..start_id + bufs.len() {
+            self.unwritten_pages.remove(&id);
+        }
+        Ok(())
+    }
+
+    // the caller is responsible for making sure the truncated pages are free
+    pub fn truncate(&mut self, page_count: usize) -> Result<()> {
+        let new_size = self.page_offset(page_count)?;
+        if new_size > self.current_size {
             return invalid_input(
-                if end > self.max_size {
-                    format!("invalid page, the specified page is beyond maximum file size (max size = {})", self.max_size)
-                } else {
-                    format!("invalid page, the specified page does not yet exist(current size = {})", self.current_size)
-                }
+                format!("invalid truncation, {} pages exceed the current size {}", page_count, self.current_size)
             );
         }
-        Ok(MemoryPage { start: offset, end, mmap: self.mmap.clone() })
+        if page_count < self.reserved_pages {
+            return permission_denied(
+                format!("reserved page, cannot truncate below the {} reserved pages", self.reserved_pages)
+            );
+        }
+        self.ensure_writable()?;
+        if Arc::strong_count(&self.mmap) > 1 {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                "mapped pages, cannot truncate while pages read from this store are still held",
+            ));
+        }
+
+        self.mapping_mut().flush()?;
+        self.file.set_len(new_size as u64)?;
+        self.unwritten_pages.retain(|&id| id < page_count);
+        self.current_size = new_size;
+        Ok(())
     }
 
-    pub fn write_page(&mut self, id: usize, buf: &[u8]) -> Result<()> {
-        if buf.len() != PAGE_SIZE {
-            return invalid_input(
-                format!("invalid size, buf needs to hold exactly {} bytes", PAGE_SIZE)
+    pub fn metrics_prometheus(&self, index: &IndexPage) -> Result<String> {
+        let (bitmap_count, bitmap_capacity) = index.bitmap_capacity();
+        let free_pages = index.chain_free_pages(self)?;
+        let largest_free_run = index.largest_free_run(self)?;
+        // 0 when all free pages form one run, approaching 1 as they scatter into single pages
+        let fragmentation = if free_pages == 0 { 0.0 } else { 1.0 - largest_free_run as f64 / free_pages as f64 };
+
+        let stats = self.stats();
+
+        let mut out = String::new();
+        gauge(&mut out, "embedb_store_size_bytes", "Current size of the database file in bytes.", stats.current_size);
+        gauge(&mut out, "embedb_store_max_size_bytes", "Maximum size of the database file in bytes.", stats.max_size);
+        gauge(&mut out, "embedb_store_pages", "Number of whole pages in the database file.", stats.page_count);
+        gauge(&mut out, "embedb_store_available_bytes", "Bytes the database file can still grow by.", stats.available_bytes);
+        counter(&mut out, "embedb_store_syncs_total", "Number of successful fsyncs since the store was opened.", self.sync_count);
+        gauge(&mut out, "embedb_index_bitmaps", "Number of bitmaps managed by the index.", bitmap_count);
+        gauge(&mut out, "embedb_index_bitmap_capacity", "Maximum number of bitmaps the index can manage.", bitmap_capacity);
+        gauge(&mut out, "embedb_index_managed_pages", "Number of pages managed by the index.", index.chain_managed_pages(self)?);
+        gauge(&mut out, "embedb_index_free_pages", "Number of free pages managed by the index.", free_pages);
+        gauge(&mut out, "embedb_index_largest_free_run_pages", "Length of the longest run of contiguous free pages.", largest_free_run);
+        gauge(&mut out, "embedb_index_free_fragmentation_ratio", "Share of free pages outside the longest free run.", fragmentation);
+        Ok(out)
+    }
+
+    pub fn verify_streaming(&self, index: &IndexPage, stop_on_first: bool) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        for (page_id, page_type, first_managed_page_id) in index.metadata_pages(self)? {
+            report.pages_checked += 1;
+            if let Some(problem) = self.verify_page(page_id, page_type, first_managed_page_id) {
+                report.problems.push((page_id, problem));
+                if stop_on_first {
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn verify_page(&self, page_id: u64, page_type: PageType, first_managed_page_id: u64) -> Option<String> {
+        let header = match self.read_header(page_id as usize) {
+            Ok(header) => header,
+            Err(e) => return Some(e.to_string()),
+        };
+        if header.page_type() != page_type as u32 {
+            return Some(format!("unexpected page type {} (expected {})", header.page_type(), page_type as u32));
+        }
+        if header_page_id(header.bytes()) != page_id {
+            return Some(format!("page claims to be page {}", header_page_id(header.bytes())));
+        }
+        if header_first_managed_page_id(header.bytes()) != first_managed_page_id {
+            return Some(format!("page manages pages from {} (expected {})", header_first_managed_page_id(header.bytes()), first_managed_page_id));
+        }
+        None
+    }
+
+    fn ensure_not_reserved(&self, id: usize) -> Result<()> {
+        if id < self.reserved_pages {
+            return permission_denied(
+                format!("reserved page, page {} can only be written as a metadata page", id)
             );
         }
-        self.write_buf_at(buf, id * PAGE_SIZE)
+        Ok(())
+    }
+
+    pub fn page_offset(&self, id: usize) -> Result<usize> {
+        match id.checked_mul(self.page_size) {
+            Some(offset) => Ok(offset),
+            None => invalid_input(
+                format!("invalid page, page {} is beyond the addressable range", id)
+            ),
+        }
+    }
+
+    pub fn offset_to_page(&self, offset: usize) -> usize {
+        offset / self.page_size
+    }
+
+    fn write_buf_at(&mut self, buf: &[u8], pos: usize) -> StoreResult<()> {
+        self.ensure_page_exists_at(pos)?;
+        self.mapping_mut()[pos..pos + buf.len()].copy_from_slice(buf);
+        self.dirty_since_flush = true;
+        self.unwritten_pages.remove(&self.offset_to_page(pos));
+        Ok(())
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return permission_denied("read-only store, pages cannot be written");
+        }
+        Ok(())
+    }
+
+    fn mapping_mut(&mut self) -> &mut MmapMut {
+        // callers go through ensure_writable first, so only writable stores get here
+        self.mmap_mut.as_mut().expect("read-only store has no writable mapping")
+    }
+
+    fn ensure_page_exists_at(&mut self, pos: usize) -> StoreResult<()> {
+        self.ensure_writable()?;
+        let new_size = (pos & (!(self.page_size - 1))) + self.page_size;
+        if new_size > self.max_size {
+            return Err(PageStoreError::PageBeyondMaxSize { max_size: self.max_size });
+        }
+        if new_size > self.current_size {
+            self.file.set_len(new_size as u64)?;
+            if self.strict_reads {
+                self.unwritten_pages.extend(self.offset_to_page(self.current_size)..self.offset_to_page(new_size));
+            }
+            self.current_size = new_size;
+        }
+        Ok(())
+    }
+}
+
+pub struct MemoryPage {
+    start: usize,
+    end: usize,
+    mmap: Arc<Mmap>,
+}
+
+impl<'a> MemoryPage {
+    pub fn page_id(&self) -> u64 {
+        header_page_id(self.content())
+    }
+
+    pub fn page_type(&self) -> u32 {
+        self.get_u32(4)
+    }
+
+    pub fn typed(&self) -> Option<PageType> {
+        PageType::try_from(self.page_type()).ok()
+    }
+
+    pub fn get_u32(&self, idx: usize) -> u32 {
+        u32::from_le_bytes(self.get_bytes(idx))
+    }
+
+    pub fn get_u16(&self, idx: usize) -> u16 {
+        u16::from_le_bytes(self.get_bytes(idx))
+    }
+
+    pub fn get_u64(&self, idx: usize) -> u64 {
+        u64::from_le_bytes(self.get_bytes(idx))
+    }
+
+    pub fn get_i32(&self, idx: usize) -> i32 {
+        i32::from_le_bytes(self.get_bytes(idx))
+    }
+
+    pub fn get_i64(&self, idx: usize) -> i64 {
+        i64::from_le_bytes(self.get_bytes(idx))
+    }
+
+    pub fn try_get_u32(&self, idx: usize) -> Option<u32> {
+        self.try_get_bytes(idx).map(u32::from_le_bytes)
+    }
+
+    pub fn try_get_u16(&self, idx: usize) -> Option<u16> {
+        self.try_get_bytes(idx).map(u16::from_le_bytes)
+    }
+
+    pub fn try_get_u64(&self, idx: usize) -> Option<u64> {
+        self.try_get_bytes(idx).map(u64::from_le_bytes)
+    }
+
+    fn get_bytes<const N: usize>(&self, idx: usize) -> [u8; N] {
+        get_bytes(self.content(), idx)
+    }
+
+    fn try_get_bytes<const N: usize>(&self, idx: usize) -> Option<[u8; N]> {
+        try_get_bytes(self.content(), idx)
+    }
+
+    pub fn content(&'a self) -> &'a [u8] {
+        &self.mmap[self.start..self.end]
+    }
+
+    #[cfg(test)]
+    pub(crate) fn truncated(&self, len: usize) -> MemoryPage {
+        MemoryPage { start: self.start, end: self.start + len, mmap: self.mmap.clone() }
+    }
+}
+
+fn get_bytes<const N: usize>(content: &[u8], idx: usize) -> [u8; N] {
+    match try_get_bytes(content, idx) {
+        Some(bytes) => bytes,
+        None => panic!("invalid offset, reading {} bytes at {} overruns page of {} bytes", N, idx, content.len()),
+    }
+}
+
+fn try_get_bytes<const N: usize>(content: &[u8], idx: usize) -> Option<[u8; N]> {
+    let end = idx.checked_add(N)?;
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(content.get(idx..end)?);
+    Some(bytes)
+}
+
+fn sync_mapping(file: &File, mmap_mut: Option<&MmapMut>) -> Result<()> {
+    // attempt both even if msync fails, so neither failure is masked by the other
+    let flushed = mmap_mut.map_or(Ok(()), |mmap_mut| mmap_mut.flush());
+    let synced = file.sync_data();
+    flushed.and(synced)
+}
+
+fn retry_with_backoff(max_attempts: usize, backoff: Duration, mut op: impl FnMut() -> Result<()>) -> Result<()> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < max_attempts && is_transient(e.kind()) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+impl Drop for PageStore {
+    fn drop(&mut self) {
+        if self.dirty_since_flush {
+            report_dropped(self.drop_policy, "page store dropped with unflushed writes");
+        }
+    }
+}
+
+fn report_dropped(drop_policy: DropPolicy, message: &str) {
+    match drop_policy {
+        DropPolicy::Silent => {}
+        DropPolicy::Warn => eprintln!("embedb: {}", message),
+        DropPolicy::Panic => {
+            if !std::thread::panicking() {
+                panic!("{}", message);
+            }
+        }
     }
+}
+
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(mmap: &Mmap, huge_pages: bool) {
+    let advice = if huge_pages { libc::MADV_HUGEPAGE } else { libc::MADV_NOHUGEPAGE };
+    // transparent huge pages are best effort, fall back to normal pages if the kernel refuses
+    unsafe {
+        libc::madvise(mmap.as_ptr() as *mut libc::c_void, mmap.len(), advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_huge_pages(_mmap: &Mmap, _huge_pages: bool) {}
+
+#[cfg(target_os = "linux")]
+fn advise_mapping(mmap: &Mmap, advice: Advice) {
+    let advice = match advice {
+        Advice::Normal => libc::MADV_NORMAL,
+        Advice::Random => libc::MADV_RANDOM,
+        Advice::Sequential => libc::MADV_SEQUENTIAL,
+        Advice::WillNeed => libc::MADV_WILLNEED,
+    };
+    unsafe {
+        libc::madvise(mmap.as_ptr() as *mut libc::c_void, mmap.len(), advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_mapping(_mmap: &Mmap, _advice: Advice) {}
+
+#[cfg(target_os = "linux")]
+fn advise_will_need(mmap: &Mmap, offset: usize, len: usize) {
+    unsafe {
+        libc::madvise(mmap.as_ptr().add(offset) as *mut libc::c_void, len, libc::MADV_WILLNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_will_need(_mmap: &Mmap, _offset: usize, _len: usize) {}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl Display) {
+    metric(out, name, help, "gauge", value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: impl Display) {
+    metric(out, name, help, "counter", value);
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// edits the page in place through the writable mapping. commit writes the page back to the file and
+// reports any error, dropping an edited page without committing is handled like an unflushed store
+pub struct PageMut<'a> {
+    store: &'a mut PageStore,
+    id: usize,
+    start: usize,
+    end: usize,
+    dirty: bool,
+}
+
+impl<'a> PageMut<'a> {
+    pub fn page_id(&self) -> usize {
+        self.id
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.store.mmap[self.start..self.end]
+    }
+
+    pub fn content_mut(&mut self) -> &mut [u8] {
+        self.dirty = true;
+        self.store.dirty_since_flush = true;
+        self.store.unwritten_pages.remove(&self.id);
+        let (start, end) = (self.start, self.end);
+        &mut self.store.mapping_mut()[start..end]
+    }
+
+    pub fn get_u32(&self, idx: usize) -> u32 {
+        u32::from_le_bytes(get_bytes(self.content(), idx))
+    }
+
+    pub fn get_u16(&self, idx: usize) -> u16 {
+        u16::from_le_bytes(get_bytes(self.content(), idx))
+    }
+
+    pub fn try_get_u32(&self, idx: usize) -> Option<u32> {
+        try_get_bytes(self.content(), idx).map(u32::from_le_bytes)
+    }
+
+    pub fn try_get_u16(&self, idx: usize) -> Option<u16> {
+        try_get_bytes(self.content(), idx).map(u16::from_le_bytes)
+    }
+
+    pub fn put_u32(&mut self, idx: usize, value: u32) -> Result<()> {
+        self.put(idx, &value.to_le_bytes())
+    }
+
+    pub fn put_u16(&mut self, idx: usize, value: u16) -> Result<()> {
+        self.put(idx, &value.to_le_bytes())
+    }
+
+    pub fn put(&mut self, idx: usize, bytes: &[u8]) -> Result<()> {
+        if idx.checked_add(bytes.len()).is_none_or(|end| end > self.end - self.start) {
+            return invalid_input(
+                "invalid (offset,size), write would overrun page"
+            );
+        }
+        self.content_mut()[idx..idx + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        if self.dirty {
+            let (start, len) = (self.start, self.end - self.start);
+            self.store.mapping_mut().flush_range(start, len)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for PageMut<'a> {
+    fn drop(&mut self) {
+        if self.dirty {
+            report_dropped(self.store.drop_policy, "page edited in place was dropped without commit");
+        }
+    }
+}
+
+pub struct DirtyBitmap<'a> {
+    store: &'a mut PageStore,
+    bitmap: Pin<Box<BitmapPage>>,
+    pending: bool,
+}
+
+impl<'a> DirtyBitmap<'a> {
+    pub fn commit(mut self) -> Result<()> {
+        self.write_back()
+    }
+
+    pub fn discard(mut self) {
+        self.pending = false;
+    }
+
+    fn write_back(&mut self) -> Result<()> {
+        if self.pending {
+            self.bitmap.persist(self.store)?;
+            self.pending = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Deref for DirtyBitmap<'a> {
+    type Target = BitmapPage;
+
+    fn deref(&self) -> &BitmapPage {
+        &self.bitmap
+    }
+}
+
+impl<'a> DerefMut for DirtyBitmap<'a> {
+    fn deref_mut(&mut self) -> &mut BitmapPage {
+        &mut self.bitmap
+    }
+}
+
+impl<'a> Drop for DirtyBitmap<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_back() {
+            report_dropped(self.store.drop_policy, &format!("dirty bitmap could not be written back on drop: {}", e));
+        }
+    }
+}
+
+const PAGE_HEADER_SNAPSHOT_SIZE: usize = 32;
+const _: () = assert!(PAGE_HEADER_SNAPSHOT_SIZE <= MIN_PAGE_SIZE);
+
+pub struct PageHeaderSnapshot {
+    bytes: [u8; PAGE_HEADER_SNAPSHOT_SIZE],
+}
+
+impl PageHeaderSnapshot {
+    pub fn page_id(&self) -> u64 {
+        header_page_id(&self.bytes)
+    }
+
+    pub fn page_type(&self) -> u32 {
+        self.get_u32(4)
+    }
+
+    pub fn get_u32(&self, idx: usize) -> u32 {
+        let mut a: [u8; 4] = [0; 4];
+        a.copy_from_slice(&self.bytes[idx..idx + 4]);
+
+        u32::from_le_bytes(a)
+    }
+
+    pub fn get_u16(&self, idx: usize) -> u16 {
+        let mut a: [u8; 2] = [0; 2];
+        a.copy_from_slice(&self.bytes[idx..idx + 2]);
+
+        u16::from_le_bytes(a)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::io::{PAGE_SIZE, PageIdWidth, PageType, header_first_managed_page_id, put_header_prefix, put_u64};
+    use crate::io::bitmap::{BitmapPage, BITMAP_PAGE_COUNT};
+    use crate::io::index::IndexPage;
+    use crate::io::slotted::SlottedPage;
+    use crate::io::store::{retry_with_backoff, Advice, DropPolicy, PageStore, PageStoreError, StoreStats};
+    use std::convert::TryFrom;
+    use std::io::{Error, Read};
+    use std::time::Duration;
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+    use std::fs::{File, OpenOptions};
+    use tempfile::{tempdir, tempfile};
+
+    const TESTDB_MAX_SIZE: usize = 163840;
+
+    #[test]
+    fn buffer_too_small() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE - 1];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        match store.write_page(0, &vec) {
+            Err(_) => (),
+            Ok(()) => panic!("should not have written the page")
+        }
+    }
+
+    #[test]
+    fn buffer_too_big() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE + 1];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        match store.write_page(0, &vec) {
+            Err(_) => (),
+            Ok(()) => panic!("should not have written the page")
+        }
+    }
+
+    #[test]
+    fn writes_first_page() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        store.write_page(0, &vec).unwrap();
+        store.flush().unwrap();
+
+        assert_eq!(PAGE_SIZE, store.current_size)
+    }
+
+    #[test]
+    fn writes_existing_page() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        store.write_page(1, &vec).unwrap();
+        store.write_page(0, &vec).unwrap();
+        store.flush().unwrap();
+
+        assert_eq!(2 * PAGE_SIZE, store.current_size)
+    }
+
+    #[test]
+    fn prefetches_range_clamped_to_current_size() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page_range(4, 0, &[1]).unwrap();
+        let prefetched = Arc::new(Mutex::new(Vec::new()));
+        let recorder = prefetched.clone();
+        store.set_prefetch_hook(move |pages| recorder.lock().unwrap().extend(pages));
+
+        store.prefetch(2, 0);
+        store.prefetch(5, 3);
+        store.prefetch(usize::MAX, 2);
+        assert!(prefetched.lock().unwrap().is_empty());
+
+        store.prefetch(3, 10);
+        assert_eq!(vec![3, 4], *prefetched.lock().unwrap());
+    }
+
+    #[test]
+    fn reads_and_writes_with_access_advice() {
+        let vec: Vec<u8> = vec![9; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        for advice in [Advice::Random, Advice::Sequential, Advice::WillNeed, Advice::Normal].iter() {
+            store.advise(*advice);
+            store.write_page(1, &vec).unwrap();
+            assert_eq!(&vec[..], store.read_page(1).unwrap().content());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reads_and_writes_with_huge_pages() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[7] = 42;
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_huge_pages(true);
+
+        store.write_page(2, &vec).unwrap();
+        store.flush().unwrap();
+
+        assert_eq!(&vec[..], store.read_page(2).unwrap().content());
+    }
+
+    #[test]
+    #[should_panic(expected = "unflushed writes")]
+    fn panics_when_dropped_with_unflushed_writes() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_drop_policy(DropPolicy::Panic);
+
+        store.write_page(0, &vec).unwrap();
+    }
+
+    #[test]
+    fn drops_cleanly_after_flush() {
+        let vec: Vec<u8> = vec![0; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_drop_policy(DropPolicy::Panic);
+
+        store.write_page(0, &vec).unwrap();
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn cannot_open_file_larger_than_max_size() {
+        let file = tempfile().unwrap();
+        file.set_len(3 * PAGE_SIZE as u64).unwrap();
+
+        let error = PageStore::new(file, 2 * PAGE_SIZE).err().unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("max size too small"), "{}", error);
+    }
+
+    #[test]
+    fn maps_the_full_max_size() {
+        let file = tempfile().unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        assert_eq!(TESTDB_MAX_SIZE, store.mmap.len());
+    }
+
+    #[test]
+    fn sequence_continues_after_reopen() {
+        let file = tempfile().unwrap();
+        let reopened = file.try_clone().unwrap();
+
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_reserved_pages(1);
+        store.set_sequence_page(0).unwrap();
+        assert_eq!(0, store.next_sequence().unwrap());
+        assert_eq!(1, store.next_sequence().unwrap());
+        assert_eq!(2, store.next_sequence_block(10).unwrap());
+        drop(store);
+
+        let mut store = PageStore::new(reopened, TESTDB_MAX_SIZE).unwrap();
+        store.set_reserved_pages(1);
+        store.set_sequence_page(0).unwrap();
+        assert_eq!(12, store.next_sequence().unwrap());
+    }
+
+    #[test]
+    fn sequence_page_must_be_reserved() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        assert_eq!(ErrorKind::InvalidInput, store.set_sequence_page(0).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, store.next_sequence().unwrap_err().kind());
+    }
+
+    #[test]
+    fn memory_page_survives_growth() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[3] = 17;
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(0, &vec).unwrap();
+        let page = store.read_page(0).unwrap();
+
+        store.write_page(TESTDB_MAX_SIZE / PAGE_SIZE - 1, &[0; PAGE_SIZE]).unwrap();
+        assert_eq!(TESTDB_MAX_SIZE, store.current_size);
+
+        assert_eq!(&vec[..], page.content());
+        assert!(Arc::ptr_eq(&page.mmap, &store.mmap));
+    }
+
+    #[test]
+    fn verifies_healthy_store() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, 2 * BITMAP_PAGE_COUNT as usize * PAGE_SIZE + 2 * PAGE_SIZE).unwrap();
+        let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+        index.persist(&mut store).unwrap();
+
+        let report = store.verify_streaming(&index, false).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(3, report.pages_checked);
+    }
+
+    #[test]
+    fn verify_reports_corrupted_pages() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, 2 * BITMAP_PAGE_COUNT as usize * PAGE_SIZE + 2 * PAGE_SIZE).unwrap();
+        let mut index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+        index.persist(&mut store).unwrap();
+
+        let bitmap_page = 2 + BITMAP_PAGE_COUNT as usize;
+        store.write_page_range(bitmap_page, 4, &7u32.to_le_bytes()).unwrap();
+        store.write_page_range(bitmap_page + 1, 0, &9u32.to_le_bytes()).unwrap();
+
+        let report = store.verify_streaming(&index, false).unwrap();
+        assert_eq!(3, report.pages_checked);
+        assert_eq!(vec![bitmap_page as u64, bitmap_page as u64 + 1], report.problems.iter().map(|(page_id, _)| *page_id).collect::<Vec<_>>());
+
+        let report = store.verify_streaming(&index, true).unwrap();
+        assert_eq!(2, report.pages_checked);
+        assert_eq!(1, report.problems.len());
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let mut attempts = 0;
+        let result = retry_with_backoff(5, Duration::from_millis(1), || {
+            attempts += 1;
+            if attempts <= 2 {
+                Err(Error::new(ErrorKind::Interrupted, "interrupted"))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(3, attempts);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts_or_permanent_errors() {
+        let mut attempts = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(Error::new(ErrorKind::Interrupted, "interrupted"))
+        });
+        assert_eq!(ErrorKind::Interrupted, result.unwrap_err().kind());
+        assert_eq!(3, attempts);
+
+        let mut attempts = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            attempts += 1;
+            Err(Error::new(ErrorKind::StorageFull, "no space left on device"))
+        });
+        assert_eq!(ErrorKind::StorageFull, result.unwrap_err().kind());
+        assert_eq!(1, attempts);
+    }
+
+    #[test]
+    fn flush_with_retry_clears_dirty_flag() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(0, &[0; PAGE_SIZE]).unwrap();
+
+        store.flush_with_retry(3, Duration::from_millis(1)).unwrap();
+        assert!(!store.dirty_since_flush);
+    }
+
+    #[test]
+    fn dirty_bitmap_persists_on_drop() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+
+        {
+            let mut bitmap = store.dirty_bitmap(2).unwrap();
+            assert_eq!(Some(3), bitmap.allocate(|_| true));
+        }
+
+        let bitmap = BitmapPage::load_into(&store.read_page(2).unwrap(), 2).unwrap();
+        assert_eq!(BITMAP_PAGE_COUNT - 2, bitmap.free_page_count);
+    }
+
+    #[test]
+    #[should_panic(expected = "could not be written back on drop")]
+    fn panics_when_dirty_bitmap_fails_to_write_back_on_drop() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+        store.flush().unwrap();
+        store.set_drop_policy(DropPolicy::Panic);
+        store.set_reserved_pages(3);
+
+        let mut bitmap = store.dirty_bitmap(2).unwrap();
+        assert_eq!(Some(3), bitmap.allocate(|_| true));
+    }
+
+    #[test]
+    fn discarded_dirty_bitmap_is_not_persisted() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+
+        let mut bitmap = store.dirty_bitmap(2).unwrap();
+        assert_eq!(Some(3), bitmap.allocate(|_| true));
+        bitmap.discard();
+
+        let bitmap = BitmapPage::load_into(&store.read_page(2).unwrap(), 2).unwrap();
+        assert_eq!(BITMAP_PAGE_COUNT - 1, bitmap.free_page_count);
+    }
+
+    #[test]
+    fn reads_and_writes_with_custom_page_size() {
+        let page_size = 4 * PAGE_SIZE;
+        let mut vec: Vec<u8> = vec![0; page_size];
+        vec[page_size - 1] = 99;
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::with_page_size(file, 4 * page_size, page_size).unwrap();
+        assert_eq!(page_size, store.page_size());
+
+        store.write_page(2, &vec).unwrap();
+        assert_eq!(3 * page_size, store.current_size);
+        assert_eq!(&vec[..], store.read_page(2).unwrap().content());
+        assert_eq!(2 * page_size, store.page_offset(2).unwrap());
+
+        store.write_page_range(1, page_size - 4, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(ErrorKind::InvalidInput, store.write_page_range(1, page_size - 3, &[1, 2, 3, 4]).unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidInput, store.write_page(0, &[0; PAGE_SIZE]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn rejects_invalid_page_size() {
+        for page_size in [0, 3000, 16, 256].iter() {
+            let file = tempfile().unwrap();
+            let result = PageStore::with_page_size(file, TESTDB_MAX_SIZE, *page_size);
+            assert_eq!(ErrorKind::InvalidInput, result.err().unwrap().kind());
+        }
+    }
+
+    #[test]
+    fn reads_back_last_page_of_mapping() {
+        let last_page = TESTDB_MAX_SIZE / PAGE_SIZE - 1;
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[..4].copy_from_slice(&(last_page as u32).to_le_bytes());
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        for id in 0..=last_page {
+            vec[..4].copy_from_slice(&(id as u32).to_le_bytes());
+            store.write_page(id, &vec).unwrap();
+        }
+
+        assert_eq!(last_page as u64, store.read_page(last_page).unwrap().page_id());
+        assert_eq!(ErrorKind::InvalidInput, store.write_page(last_page + 1, &vec).unwrap_err().kind());
+    }
+
+    #[test]
+    fn reads_own_writes_without_flush() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[100] = 5;
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &vec).unwrap();
+        assert_eq!(&vec[..], store.read_page(1).unwrap().content());
+
+        store.write_page_range(1, 100, &[6]).unwrap();
+        assert_eq!(6, store.read_page(1).unwrap().content()[100]);
+    }
+
+    #[test]
+    fn flushed_writes_reach_the_file() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[7] = 13;
+
+        let file = tempfile().unwrap();
+        let mut reader = file.try_clone().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &vec).unwrap();
+        store.flush().unwrap();
+
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(2 * PAGE_SIZE, contents.len());
+        assert_eq!(&vec[..], &contents[PAGE_SIZE..]);
+    }
+
+    #[test]
+    fn async_flush_reaches_file_but_keeps_store_dirty() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[7] = 13;
+
+        let file = tempfile().unwrap();
+        let mut reader = file.try_clone().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &vec).unwrap();
+        store.flush_async().unwrap();
+
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert_eq!(&vec[..], &contents[PAGE_SIZE..]);
+        assert!(store.dirty_since_flush);
+
+        store.flush().unwrap();
+        assert!(!store.dirty_since_flush);
+    }
+
+    #[test]
+    fn flushed_writes_survive_reopen() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[42] = 7;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &vec).unwrap();
+        store.flush().unwrap();
+        drop(store);
+
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        assert_eq!(&vec[..], store.read_page(1).unwrap().content());
+    }
+
+    #[test]
+    fn iterates_pages_of_a_type() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(1).unwrap().persist(&mut store).unwrap();
+        SlottedPage::new(3).persist(&mut store).unwrap();
+        BitmapPage::new(5).unwrap().persist(&mut store).unwrap();
+
+        let bitmaps = store.iter_pages_of_type(PageType::Bitmap).map(|page| page.page_id()).collect::<Vec<_>>();
+        let slotted = store.iter_pages_of_type(PageType::Slotted).map(|page| page.page_id()).collect::<Vec<_>>();
 
-    pub fn write_page_range(&mut self, id: usize, offset: usize, buf: &[u8]) -> Result<()> {
-        if offset + buf.len() > PAGE_SIZE {
-            return invalid_input(
-                "invalid (offset,size), write would overrun page"
-            );
-        }
-        self.write_buf_at(buf, id * PAGE_SIZE + offset)
+        assert_eq!(vec![1, 5], bitmaps);
+        assert_eq!(vec![3], slotted);
+        assert_eq!(0, store.iter_pages_of_type(PageType::Index).count());
     }
 
-    fn write_buf_at(&mut self, buf: &[u8], pos: usize) -> Result<()> {
-        self.ensure_page_exists_at(pos)?;
-        self.file.seek(SeekFrom::Start(pos as u64))?;
-        self.file.write_all(buf)?;
-        Ok(())
-    }
+    #[test]
+    fn reports_store_stats() {
+        let file = tempfile().unwrap();
+        file.set_len(PAGE_SIZE as u64 + 100).unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
 
-    fn ensure_page_exists_at(&mut self, pos: usize) -> Result<()> {
-        let new_size = (pos & (!(PAGE_SIZE - 1))) + PAGE_SIZE;
-        if new_size > self.max_size {
-            return invalid_input(
-                format!("invalid page, the specified page is beyond maximum file size ({})", self.max_size)
-            );
-        }
-        if new_size > self.current_size {
-            self.file.set_len(new_size as u64)?;
-            self.current_size = new_size;
-        }
-        Ok(())
-    }
-}
+        assert_eq!(StoreStats {
+            current_size: PAGE_SIZE + 100,
+            max_size: TESTDB_MAX_SIZE,
+            page_count: 1,
+            available_bytes: TESTDB_MAX_SIZE - PAGE_SIZE - 100,
+        }, store.stats());
 
-pub struct MemoryPage {
-    start: usize,
-    end: usize,
-    mmap: Arc<Mmap>,
-}
+        store.write_page_range(2, 0, &[1]).unwrap();
 
-impl<'a> MemoryPage {
-    pub fn page_id(&self) -> u32 {
-        self.get_u32(0)
+        assert_eq!(3, store.stats().page_count);
+        assert_eq!(TESTDB_MAX_SIZE - 3 * PAGE_SIZE, store.stats().available_bytes);
     }
 
-    pub fn page_type(&self) -> u32 {
-        self.get_u32(4)
-    }
+    #[test]
+    fn read_only_store_rejects_writes() {
+        let vec: Vec<u8> = vec![3; PAGE_SIZE];
 
-    pub fn get_u32(&self, idx: usize) -> u32 {
-        let s = &self.content()[idx..idx + 4];
-        let mut a: [u8; 4] = [0; 4];
-        a.copy_from_slice(s);
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path).unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(0, &vec).unwrap();
+        store.flush().unwrap();
+        drop(store);
 
-        u32::from_le_bytes(a)
+        let mut store = PageStore::open_read_only(File::open(&path).unwrap(), PAGE_SIZE).unwrap();
+        assert!(store.is_read_only());
+        assert_eq!(&vec[..], store.read_page(0).unwrap().content());
+
+        assert_eq!(ErrorKind::PermissionDenied, store.write_page(0, &vec).unwrap_err().kind());
+        assert_eq!(ErrorKind::PermissionDenied, store.write_page_range(1, 0, &[1]).unwrap_err().kind());
+        assert_eq!(ErrorKind::PermissionDenied, store.write_page_mut(0).err().unwrap().kind());
+        assert_eq!(PAGE_SIZE as u64, File::open(&path).unwrap().metadata().unwrap().len());
     }
 
-    pub fn get_u16(&self, idx: usize) -> u16 {
-        let s = &self.content()[idx..idx + 2];
-        let mut a: [u8; 2] = [0; 2];
-        a.copy_from_slice(s);
+    #[test]
+    fn mutates_page_in_place_through_mapping() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_strict_reads(true);
 
-        u16::from_le_bytes(a)
-    }
+        {
+            let mut page = store.write_page_mut(2).unwrap();
+            assert_eq!(2, page.page_id());
+            page.put_u32(0, 0xDEAD_BEEF).unwrap();
+            page.put_u16(8, 0x1234).unwrap();
+            assert_eq!(0xDEAD_BEEF, page.get_u32(0));
+            assert_eq!(None, page.try_get_u32(PAGE_SIZE - 2));
+            assert_eq!(ErrorKind::InvalidInput, page.put_u32(PAGE_SIZE - 2, 1).unwrap_err().kind());
+            page.commit().unwrap();
+        }
+        assert!(store.dirty_since_flush);
 
-    pub fn content(&'a self) -> &'a [u8] {
-        &self.mmap[self.start..self.end]
+        let page = store.read_page(2).unwrap();
+        assert_eq!(0xDEAD_BEEF, page.get_u32(0));
+        assert_eq!(0x1234, page.get_u16(8));
+        assert_eq!(ErrorKind::InvalidData, store.read_page(1).err().unwrap().kind());
     }
-}
-
 
-#[cfg(test)]
-mod tests {
-    use crate::io::PAGE_SIZE;
-    use crate::io::store::PageStore;
-    use tempfile::tempfile;
+    #[test]
+    fn cannot_mutate_reserved_page_in_place() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_reserved_pages(1);
 
-    const TESTDB_MAX_SIZE: usize = 163840;
+        assert_eq!(ErrorKind::PermissionDenied, store.write_page_mut(0).err().unwrap().kind());
+    }
 
     #[test]
-    fn buffer_too_small() {
-        let vec: Vec<u8> = vec![0; PAGE_SIZE - 1];
+    fn reads_64_bit_and_signed_values() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        put_u64(&mut vec, 8, 0x0102_0304_0506_0708);
+        vec[16..20].copy_from_slice(&(-5i32).to_le_bytes());
+        vec[PAGE_SIZE - 8..].copy_from_slice(&i64::MIN.to_le_bytes());
 
         let file = tempfile().unwrap();
         let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(0, &vec).unwrap();
 
-        match store.write_page(0, &vec) {
-            Err(_) => (),
-            Ok(()) => panic!("should not have written the page")
-        }
+        let page = store.read_page(0).unwrap();
+        assert_eq!(0x0102_0304_0506_0708, page.get_u64(8));
+        assert_eq!(0x0506_0708, page.get_u32(8));
+        assert_eq!(-5, page.get_i32(16));
+        assert_eq!(i64::MIN, page.get_i64(PAGE_SIZE - 8));
     }
 
     #[test]
-    fn buffer_too_big() {
-        let vec: Vec<u8> = vec![0; PAGE_SIZE + 1];
-
+    #[should_panic(expected = "overruns page")]
+    fn reading_past_page_end_panics() {
         let file = tempfile().unwrap();
         let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(0, &[0; PAGE_SIZE]).unwrap();
 
-        match store.write_page(0, &vec) {
-            Err(_) => (),
-            Ok(()) => panic!("should not have written the page")
-        }
+        store.read_page(0).unwrap().get_u64(PAGE_SIZE - 4);
     }
 
     #[test]
-    fn writes_first_page() {
-        let vec: Vec<u8> = vec![0; PAGE_SIZE];
+    fn checked_reads_stop_at_page_end() {
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[PAGE_SIZE - 4..].copy_from_slice(&0xDEAD_BEEFu32.to_le_bytes());
 
         let file = tempfile().unwrap();
         let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
-
         store.write_page(0, &vec).unwrap();
-        store.flush().unwrap();
 
-        assert_eq!(PAGE_SIZE, store.current_size)
+        let page = store.read_page(0).unwrap();
+        assert_eq!(Some(0xDEAD_BEEF), page.try_get_u32(PAGE_SIZE - 4));
+        assert_eq!(None, page.try_get_u32(PAGE_SIZE - 3));
+        assert_eq!(Some(0xDEAD), page.try_get_u16(PAGE_SIZE - 2));
+        assert_eq!(None, page.try_get_u16(PAGE_SIZE - 1));
+        assert_eq!(None, page.try_get_u64(PAGE_SIZE - 7));
+        assert_eq!(None, page.try_get_u32(usize::MAX));
     }
 
     #[test]
-    fn writes_existing_page() {
+    fn maps_stored_page_type_to_enum() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        BitmapPage::new(2).unwrap().persist(&mut store).unwrap();
+        SlottedPage::new(3).persist(&mut store).unwrap();
+        let mut vec: Vec<u8> = vec![0; PAGE_SIZE];
+        vec[4..8].copy_from_slice(&42u32.to_le_bytes());
+        store.write_page(5, &vec).unwrap();
+
+        assert_eq!(Some(PageType::Bitmap), store.read_page(2).unwrap().typed());
+        assert_eq!(Some(PageType::Slotted), store.read_page(3).unwrap().typed());
+        assert_eq!(None, store.read_page(5).unwrap().typed());
+        assert_eq!(PageType::FreeList, PageType::try_from(4).unwrap());
+        assert_eq!(ErrorKind::InvalidData, PageType::try_from(0).unwrap_err().kind());
+    }
+
+    #[test]
+    fn flush_without_writes_is_a_no_op() {
         let vec: Vec<u8> = vec![0; PAGE_SIZE];
 
         let file = tempfile().unwrap();
         let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        assert!(!store.dirty_since_flush);
 
-        store.write_page(1, &vec).unwrap();
         store.write_page(0, &vec).unwrap();
+        assert!(store.dirty_since_flush);
+
         store.flush().unwrap();
+        assert!(!store.dirty_since_flush);
+        assert_eq!(1, store.sync_count());
 
-        assert_eq!(2 * PAGE_SIZE, store.current_size)
+        store.flush().unwrap();
+        assert!(!store.dirty_since_flush);
+        assert_eq!(1, store.sync_count());
     }
 
     #[test]
@@ -232,6 +1607,73 @@ mod tests {
         assert_eq!(PAGE_SIZE, store.current_size);
     }
 
+    #[test]
+    fn copies_page_and_rewrites_its_id() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        let mut page = SlottedPage::new(1);
+        page.insert(b"snapshot").unwrap();
+        page.persist(&mut store).unwrap();
+
+        store.copy_page(1, 4).unwrap();
+
+        let source = store.read_page(1).unwrap();
+        let copy = store.read_page(4).unwrap();
+        assert_eq!(1, source.page_id());
+        assert_eq!(4, copy.page_id());
+        assert_eq!(&source.content()[4..], &copy.content()[4..]);
+        assert_eq!(Some(&b"snapshot"[..]), SlottedPage::load(&copy).unwrap().get(0));
+        assert_eq!(5 * PAGE_SIZE, store.current_size);
+    }
+
+    #[test]
+    fn copies_wide_page_and_rewrites_its_full_id() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        let mut buffer = vec![0u8; PAGE_SIZE];
+        put_header_prefix(&mut buffer, PageType::WideBitmap, PageIdWidth::U64, (1 << 32) + 1, 5_000_000_000);
+        store.write_page(1, &buffer).unwrap();
+
+        store.copy_page(1, 4).unwrap();
+
+        assert_eq!((1 << 32) + 1, store.read_page(1).unwrap().page_id());
+        let copy = store.read_page(4).unwrap();
+        assert_eq!(4, copy.page_id());
+        assert_eq!(4, store.read_header(4).unwrap().page_id());
+        assert_eq!(5_000_000_000, header_first_managed_page_id(copy.content()));
+    }
+
+    #[test]
+    fn zeroes_page_in_place() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &[0xAB; PAGE_SIZE]).unwrap();
+        store.write_page(2, &[0xCD; PAGE_SIZE]).unwrap();
+
+        store.zero_page(1).unwrap();
+
+        assert!(store.read_page(1).unwrap().content().iter().all(|byte| *byte == 0));
+        assert!(store.read_page(2).unwrap().content().iter().all(|byte| *byte == 0xCD));
+        assert!(!store.dirty_since_flush);
+        assert!(matches!(store.zero_page(3), Err(PageStoreError::PageNotYetAllocated { .. })));
+        assert!(matches!(store.zero_page(TESTDB_MAX_SIZE / PAGE_SIZE), Err(PageStoreError::PageBeyondMaxSize { .. })));
+        assert_eq!(3 * PAGE_SIZE, store.current_size);
+    }
+
+    #[test]
+    fn range_ending_in_new_page_grows_store() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_strict_reads(true);
+        store.write_page(0, &[0; PAGE_SIZE]).unwrap();
+
+        store.write_page_range(1, PAGE_SIZE - 4, &[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(2 * PAGE_SIZE, store.current_size);
+        assert!(store.unwritten_pages.is_empty());
+        assert_eq!(&[1, 2, 3, 4], &store.read_page(1).unwrap().content()[PAGE_SIZE - 4..]);
+    }
+
     #[test]
     fn cannot_read_beyond_current_file_size() {
         let vec: Vec<u8> = vec![1, 2, 3, 4, 5];
@@ -244,6 +1686,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn writes_many_pages_of_store_size_at_once() {
+        let page_size = 2 * PAGE_SIZE;
+        let bufs = vec![vec![1u8; page_size], vec![2u8; page_size]];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::with_page_size(file, TESTDB_MAX_SIZE, page_size).unwrap();
+        store.write_pages(1, &bufs).unwrap();
+
+        assert_eq!(3 * page_size, store.current_size);
+        assert_eq!(&bufs[1][..], store.read_page(2).unwrap().content());
+        assert!(matches!(store.write_pages(1, &[[0u8; PAGE_SIZE]]), Err(PageStoreError::BufferSizeMismatch { expected, got: PAGE_SIZE }) if expected == page_size));
+    }
+
+    #[test]
+    fn writes_many_pages_at_once() {
+        let bufs = [[1u8; PAGE_SIZE], [2u8; PAGE_SIZE], [3u8; PAGE_SIZE]];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_strict_reads(true);
+        store.write_pages(1, &bufs).unwrap();
+
+        assert_eq!(4 * PAGE_SIZE, store.current_size);
+        for (i, buf) in bufs.iter().enumerate() {
+            assert_eq!(&buf[..], store.read_page(1 + i).unwrap().content());
+        }
+        let last_page = TESTDB_MAX_SIZE / PAGE_SIZE - 1;
+        assert!(matches!(store.write_pages(last_page - 1, &bufs), Err(PageStoreError::PageBeyondMaxSize { .. })));
+        assert_eq!(4 * PAGE_SIZE, store.current_size);
+    }
+
+    #[test]
+    fn truncates_freed_pages_at_the_end() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file.try_clone().unwrap(), TESTDB_MAX_SIZE).unwrap();
+        store.write_page_range(2, 0, &[1]).unwrap();
+
+        let held = store.read_page(0).unwrap();
+        assert_eq!(ErrorKind::ResourceBusy, store.truncate(1).unwrap_err().kind());
+        drop(held);
+
+        store.truncate(1).unwrap();
+
+        assert_eq!(PAGE_SIZE as u64, file.metadata().unwrap().len());
+        assert!(matches!(store.read_page(1), Err(PageStoreError::PageNotYetAllocated { current_size: PAGE_SIZE })));
+        assert_eq!(ErrorKind::InvalidInput, store.truncate(2).unwrap_err().kind());
+    }
+
+    #[test]
+    fn reports_typed_errors() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page_range(0, 0, &[1]).unwrap();
+
+        assert!(matches!(store.read_page(1), Err(PageStoreError::PageNotYetAllocated { current_size: PAGE_SIZE })));
+        assert!(matches!(store.read_page(TESTDB_MAX_SIZE / PAGE_SIZE), Err(PageStoreError::PageBeyondMaxSize { .. })));
+        assert!(matches!(store.write_page(0, &[0; 3]), Err(PageStoreError::BufferSizeMismatch { expected: PAGE_SIZE, got: 3 })));
+        assert!(matches!(store.write_page_range(0, PAGE_SIZE - 1, &[0; 2]), Err(PageStoreError::WriteOverrunsPage)));
+        assert_eq!(ErrorKind::InvalidInput, Error::from(store.read_page(1).err().unwrap()).kind());
+    }
+
     #[test]
     fn read_back_page() {
         let vec: Vec<u8> = vec![1, 2, 3, 4, 5];
@@ -255,6 +1759,229 @@ mod tests {
         let page = store.read_page(0).unwrap();
 
         assert_eq!(&vec[0..5], &page.content()[0..5]);
-        assert_eq!(0 as u8, page.content()[PAGE_SIZE - 1])
+        assert_eq!(0u8, page.content()[PAGE_SIZE - 1])
+    }
+
+    #[test]
+    fn read_header_snapshot() {
+        let vec: Vec<u8> = vec![7, 0, 0, 0, 2, 0, 0, 0, 9];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        store.write_page_range(1, 0, &vec).unwrap();
+        let header = store.read_header(1).unwrap();
+
+        assert_eq!(1, Arc::strong_count(&store.mmap));
+        assert_eq!(7, header.page_id());
+        assert_eq!(2, header.page_type());
+        assert_eq!(9, header.get_u16(8));
+        assert_eq!(&vec[..], &header.bytes()[0..9]);
+    }
+
+    #[test]
+    fn cannot_read_header_beyond_current_file_size() {
+        let file = tempfile().unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        assert!(store.read_header(0).is_err());
+    }
+
+    #[test]
+    fn patches_page_in_place() {
+        let vec: Vec<u8> = vec![1; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &vec).unwrap();
+
+        {
+            let mut page = store.page_mut(1).unwrap();
+            page.put_u32(100, 0x0403_0201).unwrap();
+            page.content_mut()[104] = 5;
+            page.commit().unwrap();
+        }
+        store.flush().unwrap();
+
+        let page = store.read_page(1).unwrap();
+        assert_eq!(&[1, 1, 2, 3, 4, 5, 1], &page.content()[99..106]);
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped without commit")]
+    fn panics_when_edited_page_is_dropped_without_commit() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &[1; PAGE_SIZE]).unwrap();
+        store.flush().unwrap();
+        store.set_drop_policy(DropPolicy::Panic);
+
+        let mut page = store.page_mut(1).unwrap();
+        page.put_u16(0, 2).unwrap();
+    }
+
+    #[test]
+    fn untouched_page_needs_no_commit() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &[1; PAGE_SIZE]).unwrap();
+        store.flush().unwrap();
+        store.set_drop_policy(DropPolicy::Panic);
+
+        let page = store.page_mut(1).unwrap();
+        assert_eq!(0x0101, page.get_u16(0));
+    }
+
+    #[test]
+    fn page_mut_rejects_overrun() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page_range(0, 0, &[1]).unwrap();
+
+        let mut page = store.page_mut(0).unwrap();
+        assert!(page.put_u32(PAGE_SIZE - 3, 1).is_err());
+        assert!(page.put_u16(PAGE_SIZE - 2, 1).is_ok());
+    }
+
+    #[test]
+    fn cannot_patch_missing_page() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        assert!(store.page_mut(0).is_err());
+    }
+
+    #[test]
+    fn strict_read_of_unwritten_page() {
+        let vec: Vec<u8> = vec![1; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_strict_reads(true);
+
+        store.write_page(3, &vec).unwrap();
+        assert!(matches!(store.read_page(1), Err(PageStoreError::PageUninitialized { id: 1 })));
+        store.read_page(3).unwrap();
+
+        store.write_page(1, &vec).unwrap();
+        assert_eq!(&vec[..], store.read_page(1).unwrap().content());
+    }
+
+    #[test]
+    fn lenient_store_does_not_track_unwritten_pages() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        store.write_page(30, &[1; PAGE_SIZE]).unwrap();
+
+        assert!(store.unwritten_pages.is_empty());
+        store.read_page(1).unwrap();
+    }
+
+    #[test]
+    fn lenient_read_of_unwritten_page() {
+        let vec: Vec<u8> = vec![1; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        store.write_page(3, &vec).unwrap();
+        assert_eq!(0, store.read_page(1).unwrap().content()[0]);
+    }
+
+    #[test]
+    fn converts_between_pages_and_offsets() {
+        let file = tempfile().unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        for id in [0, 1, 39, 40, usize::MAX / PAGE_SIZE].iter() {
+            let offset = store.page_offset(*id).unwrap();
+            assert_eq!(*id, store.offset_to_page(offset));
+        }
+        assert_eq!(PAGE_SIZE, store.page_offset(1).unwrap());
+        assert_eq!(1, store.offset_to_page(PAGE_SIZE + 17));
+        assert!(store.page_offset(usize::MAX / PAGE_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn cannot_read_page_beyond_addressable_range() {
+        let file = tempfile().unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+
+        assert!(store.read_page(usize::MAX / PAGE_SIZE).is_err());
+        assert!(store.read_page(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn cannot_write_reserved_page() {
+        let vec: Vec<u8> = vec![1; PAGE_SIZE];
+
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.set_reserved_pages(1);
+
+        for result in [
+            store.write_page(0, &vec).map_err(Error::from),
+            store.write_page_range(0, 8, &vec[0..8]).map_err(Error::from),
+            store.page_mut(0).map(|_| ()),
+        ].iter() {
+            match result {
+                Err(e) => assert_eq!(ErrorKind::PermissionDenied, e.kind()),
+                Ok(_) => panic!("should not have written a reserved page")
+            }
+        }
+
+        store.write_metadata_page(0, &vec).unwrap();
+        store.write_page(1, &vec).unwrap();
+        assert_eq!(&vec[..], store.read_page(0).unwrap().content());
+    }
+
+    #[test]
+    fn exports_prometheus_metrics() {
+        let file = tempfile().unwrap();
+        let mut store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        store.write_page(1, &[0; PAGE_SIZE]).unwrap();
+        store.flush().unwrap();
+        let index = IndexPage::grow(BitmapPage::new(2).unwrap()).unwrap();
+
+        let metrics = store.metrics_prometheus(&index).unwrap();
+
+        for name in ["embedb_store_size_bytes", "embedb_store_max_size_bytes", "embedb_store_pages",
+            "embedb_store_available_bytes", "embedb_index_bitmaps", "embedb_index_bitmap_capacity",
+            "embedb_index_managed_pages", "embedb_index_free_pages", "embedb_index_largest_free_run_pages",
+            "embedb_index_free_fragmentation_ratio"].iter() {
+            assert!(metrics.contains(&format!("# TYPE {} gauge\n", name)));
+        }
+        assert!(metrics.contains("# TYPE embedb_store_syncs_total counter\n"));
+        assert!(metrics.contains(&format!("\nembedb_store_max_size_bytes {}\n", TESTDB_MAX_SIZE)));
+        assert!(metrics.contains("\nembedb_store_pages 2\n"));
+        assert!(metrics.contains(&format!("\nembedb_store_available_bytes {}\n", TESTDB_MAX_SIZE - 2 * PAGE_SIZE)));
+        assert!(metrics.contains("\nembedb_store_syncs_total 1\n"));
+        assert!(metrics.contains(&format!("\nembedb_index_free_pages {}\n", index.total_free_pages())));
+        assert_eq!(2 * BITMAP_PAGE_COUNT as u64 - 3, index.total_free_pages());
+    }
+
+    #[test]
+    fn exports_free_run_and_fragmentation_metrics() {
+        let file = tempfile().unwrap();
+        let store = PageStore::new(file, TESTDB_MAX_SIZE).unwrap();
+        let mut first = BitmapPage::new(2).unwrap();
+        assert!(first.reserve(3..2 + BITMAP_PAGE_COUNT as u64));
+        let mut index = IndexPage::grow(first).unwrap();
+        for _ in 2..BITMAP_PAGE_COUNT {
+            index.allocate(&store, &mut |_| true).unwrap().unwrap();
+        }
+        // 100 free pages, half of them in one run and half scattered
+        let second = 2 + BITMAP_PAGE_COUNT as u64;
+        for page_id in (second + 10..second + 60).chain((second + 100..second + 200).step_by(2)) {
+            index.free(page_id, &store, &mut |_| true).unwrap().unwrap();
+        }
+        assert_eq!(100, index.total_free_pages());
+
+        let metrics = store.metrics_prometheus(&index).unwrap();
+
+        assert_eq!(50, index.largest_free_run(&store).unwrap());
+        assert!(metrics.contains("\nembedb_index_largest_free_run_pages 50\n"));
+        assert!(metrics.contains("\nembedb_index_free_fragmentation_ratio 0.5\n"));
     }
-}
\ No newline at end of file
+}
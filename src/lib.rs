@@ -1,11 +1,21 @@
+pub mod db;
 pub mod io;
 
 
 
 #[cfg(test)]
 mod tests {
+    use crate::db::Database;
+    use crate::io::bitmap::BITMAP_PAGE_COUNT;
+    use tempfile::tempdir;
+
     #[test]
     fn creates_new_database_file() {
-        assert_eq!(2 + 2, 4);
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.db");
+
+        Database::open(&path, 3 * BITMAP_PAGE_COUNT as usize * 4096).unwrap();
+
+        assert!(path.exists());
     }
 }